@@ -0,0 +1,85 @@
+use crate::error::{RopsError, RopsResult};
+use reqwest::blocking::Client;
+use serde::{Deserialize, Serialize};
+use std::io::Write;
+
+#[derive(Debug, Default, Clone, Deserialize, Serialize)]
+pub struct AuditSettings {
+    /// file where a JSON line is appended for each deploy
+    pub file: Option<String>,
+    /// url where each deploy record is posted as JSON
+    pub webhook: Option<String>,
+}
+
+/// Record of a chart deploy
+#[derive(Debug, Default, Clone, Deserialize, Serialize)]
+pub struct AuditRecord {
+    pub timestamp: String,
+    pub chart: String,
+    pub env: String,
+    pub cluster: String,
+    pub namespace: String,
+    pub release: String,
+    pub user: String,
+    pub sha: String,
+    pub branch: String,
+    pub image_tag: String,
+    pub rops_version: String,
+    pub success: bool,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+impl AuditSettings {
+    /// Append the record to the audit file and post it to the webhook when configured
+    ///
+    /// Failures are logged rather than returned so that auditing never fails a deploy
+    pub fn record(&self, record: &AuditRecord) {
+        if let Some(file) = self.file.as_ref()
+            && let Err(err) = Self::append(file, record)
+        {
+            log::error!("Failed to write audit record to '{file}': {err}");
+        }
+        if let Some(webhook) = self.webhook.as_ref()
+            && let Err(err) = Self::post(webhook, record)
+        {
+            log::error!("Failed to post audit record to '{webhook}': {err}");
+        }
+    }
+
+    fn append(file: &str, record: &AuditRecord) -> RopsResult<()> {
+        let mut audit_file = std::fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(file)?;
+        writeln!(audit_file, "{}", serde_json::to_string(record)?)?;
+        Ok(())
+    }
+
+    fn post(webhook: &str, record: &AuditRecord) -> RopsResult<()> {
+        let response = Client::new()
+            .post(webhook)
+            .header("User-Agent", "quantmind/rops")
+            .json(record)
+            .send()?;
+        if response.status().is_success() {
+            Ok(())
+        } else {
+            Err(RopsError::Error(format!(
+                "status {}: {}",
+                response.status(),
+                response.text()?
+            )))
+        }
+    }
+}
+
+impl AuditRecord {
+    /// Release description recorded by helm for the revision
+    pub fn description(&self) -> String {
+        format!(
+            "rops/{} user={} sha={} branch={}",
+            self.rops_version, self.user, self.sha, self.branch
+        )
+    }
+}
@@ -3,7 +3,7 @@ use crate::{
     settings::Settings,
     utils::as_true,
 };
-use reqwest::{Method, blocking::Client};
+use reqwest::{Method, Url, blocking::Client};
 use serde::{Deserialize, Serialize};
 
 #[derive(Debug, Clone, Deserialize, Serialize)]
@@ -15,6 +15,7 @@ pub struct BlockSettings {
 }
 
 #[derive(Debug, Default, Clone, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
 pub struct BlockConfig {
     pub name: String,
     pub space: Option<String>,
@@ -30,6 +31,7 @@ pub struct BlockConfig {
 }
 
 #[derive(Debug, Default, Clone, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
 pub struct Route {
     pub name: String,
     pub protocols: Vec<String>,
@@ -43,6 +45,7 @@ pub struct Route {
 }
 
 #[derive(Debug, Default, Clone, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
 pub struct Plugin {
     pub name: String,
     pub config: serde_json::Value, // Use serde_json::Value for flexible plugin configuration
@@ -79,6 +82,86 @@ impl Default for BlockSettings {
     }
 }
 
+const ROUTE_PROTOCOLS: &[&str] = &[
+    "http", "https", "grpc", "grpcs", "tcp", "tls", "udp", "ws", "wss",
+];
+
+impl BlockConfig {
+    /// The block of a release - `{release}` and `{namespace}` in the upstream are
+    /// replaced with the release name and namespace of the deploy
+    pub fn for_release(&self, release: &str, namespace: &str) -> Self {
+        Self {
+            upstream: self
+                .upstream
+                .replace("{release}", release)
+                .replace("{namespace}", namespace),
+            ..self.clone()
+        }
+    }
+
+    /// Validate the block configuration and return a list of errors
+    pub fn validate(&self, prefix: &str) -> Vec<String> {
+        let mut errors = vec![];
+        if self.name.is_empty() {
+            errors.push(format!("{prefix}.name: must not be empty"));
+        }
+        if let Err(err) = Url::parse(&self.for_release("release", "namespace").upstream) {
+            errors.push(format!(
+                "{prefix}.upstream: invalid url '{}' - {err}",
+                self.upstream
+            ));
+        }
+        if self.routes.is_empty() {
+            errors.push(format!("{prefix}.routes: at least one route is required"));
+        }
+        let mut route_names = std::collections::HashSet::new();
+        for (index, route) in self.routes.iter().enumerate() {
+            let route_prefix = format!("{prefix}.routes[{index}]");
+            if route.name.is_empty() {
+                errors.push(format!("{route_prefix}.name: must not be empty"));
+            } else if !route_names.insert(route.name.as_str()) {
+                errors.push(format!(
+                    "{route_prefix}.name: duplicate route name '{}'",
+                    route.name
+                ));
+            }
+            if route.protocols.is_empty() {
+                errors.push(format!(
+                    "{route_prefix}.protocols: at least one protocol is required"
+                ));
+            }
+            for protocol in route.protocols.iter() {
+                if !ROUTE_PROTOCOLS.contains(&protocol.as_str()) {
+                    errors.push(format!(
+                        "{route_prefix}.protocols: unknown protocol '{protocol}' - expected one of {}",
+                        ROUTE_PROTOCOLS.join(", ")
+                    ));
+                }
+            }
+            if route.paths.is_empty() {
+                errors.push(format!(
+                    "{route_prefix}.paths: at least one path is required"
+                ));
+            }
+            for path in route.paths.iter() {
+                if !path.starts_with('/') {
+                    errors.push(format!(
+                        "{route_prefix}.paths: path '{path}' must start with '/'"
+                    ));
+                }
+            }
+            for (plugin_index, plugin) in route.plugins.iter().enumerate() {
+                if plugin.name.is_empty() {
+                    errors.push(format!(
+                        "{route_prefix}.plugins[{plugin_index}].name: must not be empty"
+                    ));
+                }
+            }
+        }
+        errors
+    }
+}
+
 impl BlockSettings {
     pub fn metablock(&self) -> RopsResult<Metablock> {
         let api_token = std::env::var("METABLOCK_API_TOKEN").map_err(|_| {
@@ -138,6 +221,37 @@ impl Metablock {
         Ok(())
     }
 
+    pub fn remove(
+        &self,
+        settings: &Settings,
+        block_config: &BlockConfig,
+        dry_run: bool,
+    ) -> RopsResult<()> {
+        let space_name = block_config
+            .space
+            .clone()
+            .unwrap_or_else(|| settings.blocks.default_space.clone());
+        match self.get_block(&space_name, &block_config.name)? {
+            Some(block) if dry_run => {
+                log::info!(
+                    "Dry run mode enabled, skipping deletion of block '{}'",
+                    block.full_name
+                );
+            }
+            Some(block) => {
+                self.delete_block(&block.id)?;
+                log::info!("Block '{}' deleted", block.full_name);
+            }
+            None => {
+                log::info!(
+                    "Block '{}' not found in space '{space_name}' - nothing to delete",
+                    block_config.name,
+                );
+            }
+        }
+        Ok(())
+    }
+
     pub fn get_block(&self, space_name: &str, block_name: &str) -> RopsResult<Option<Block>> {
         let url = format!(
             "{}/v1/spaces/{space_name}/blocks?name={block_name}",
@@ -177,4 +291,17 @@ impl Metablock {
         }
         Ok(response.json()?)
     }
+
+    pub fn delete_block(&self, block_id: &str) -> RopsResult<()> {
+        let url = format!("{}/v1/blocks/{block_id}", self.api_url);
+        let response = self.request(Method::DELETE, url).send()?;
+        if response.status().is_client_error() {
+            return Err(RopsError::Error(format!(
+                "Failed to delete block - status {}: {}",
+                response.status(),
+                response.text()?
+            )));
+        }
+        Ok(())
+    }
 }
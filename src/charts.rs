@@ -1,25 +1,54 @@
 use crate::{
+    audit::{AuditRecord, AuditSettings},
     blocks::BlockConfig,
+    docker::{DockerSettings, image_digest, image_reference},
+    drift::{Drift, DriftStatus, ReleaseStatus, diff_values, value_at},
     error::{RopsError, RopsResult},
-    git::GitSettings,
+    git::{GitRepo, GitSettings},
+    health::HealthCheck,
+    helm_repos::{HelmRepos, ReposCommand},
+    kube::{Event, KubeClient, Pod},
+    locks::{Acquired, DeployLock, LockInfo},
+    manifests::{ChartType, Manifests, RELEASE_LABEL},
+    notifications::Notification,
+    preview::PreviewNamespace,
+    prompt,
+    scaffold::ChartScaffold,
+    secrets::Sops,
     settings::Settings,
-    utils::{StreamCommand, as_true},
+    tools::ToolsCommand,
+    utils::{
+        StreamCommand, as_true, glob_match, merge_yaml, now_rfc3339, set_yaml_path,
+        split_set_values,
+    },
 };
+use reqwest::Url;
+use semver::VersionReq;
 use serde::{Deserialize, Serialize};
 use std::fs;
-use std::{collections::HashMap, path::Path, process::Command};
+use std::io::{BufRead, BufReader, IsTerminal, Write};
+use std::time::{Duration, Instant};
+use std::{
+    collections::{BTreeMap, HashMap},
+    path::{Path, PathBuf},
+    process::{Command, Stdio},
+};
+use tempfile::NamedTempFile;
 
 #[derive(clap::Subcommand, Debug, Clone)]
 pub enum ChartsCommand {
     /// List all available charts
     #[command(alias = "ls")]
     List,
-    /// Update helm plugins
+    /// Update sops, used to decrypt chart secrets
     Update,
+    /// Manage the helm repos of charts
+    #[command(subcommand)]
+    Repos(ReposCommand),
     /// Deploy a chart
     Deploy {
-        /// The name of the chart
-        chart: String,
+        /// The name of the chart - picked interactively if not given
+        chart: Option<String>,
         /// K8s environment to deploy to
         #[arg(short, long)]
         env: Option<String>,
@@ -29,6 +58,9 @@ pub enum ChartsCommand {
         /// override additional variables path
         #[arg(short, long)]
         vars: Option<String>,
+        /// Local values files applied over the configured value layers
+        #[arg(short = 'f', long, num_args = 1..)]
+        values: Vec<String>,
         /// Additional deploy arguments
         #[arg(short, long, num_args = 1..)]
         args: Vec<String>,
@@ -44,14 +76,319 @@ pub enum ChartsCommand {
         /// Dry run the deployment
         #[arg(long, action = clap::ArgAction::SetTrue)]
         dry_run: Option<bool>,
+        /// Image tag to deploy - defaults to the git tag of the current commit
+        #[arg(long)]
+        image_tag: Option<String>,
+        /// Verify chart images exist in the registry before deploying
+        #[arg(long, action = clap::ArgAction::SetTrue)]
+        verify_images: Option<bool>,
+        /// Break the deploy lock of the release if held by someone else
+        #[arg(long, action = clap::ArgAction::SetTrue)]
+        force: Option<bool>,
+        /// Skip the confirmation of protected environments
+        #[arg(short, long, action = clap::ArgAction::SetTrue)]
+        yes: Option<bool>,
+    },
+    /// Render chart manifests locally without deploying
+    Template {
+        /// The name of the chart
+        chart: String,
+        /// K8s environment to render values for
+        #[arg(short, long)]
+        env: Option<String>,
+        /// The namespace to render the chart in
+        #[arg(short, long)]
+        namespace: Option<String>,
+        /// override additional variables path
+        #[arg(short, long)]
+        vars: Option<String>,
+        /// Local values files applied over the configured value layers
+        #[arg(short = 'f', long, num_args = 1..)]
+        values: Vec<String>,
+        /// Additional template arguments
+        #[arg(short, long, num_args = 1..)]
+        args: Vec<String>,
+        /// Additional set values
+        #[arg(short, long, num_args = 1..)]
+        set: Vec<String>,
+        /// Write rendered manifests to this directory rather than stdout
+        #[arg(short, long)]
+        output: Option<String>,
+        /// Image tag to render - defaults to the git tag of the current commit
+        #[arg(long)]
+        image_tag: Option<String>,
+    },
+    /// Print the effective values of a chart - value layers merged with set values
+    Values {
+        /// The name of the chart
+        chart: String,
+        /// K8s environment to resolve values for
+        #[arg(short, long)]
+        env: Option<String>,
+        /// The namespace of the chart
+        #[arg(short, long)]
+        namespace: Option<String>,
+        /// override additional variables path
+        #[arg(short, long)]
+        vars: Option<String>,
+        /// Local values files applied over the configured value layers
+        #[arg(short = 'f', long, num_args = 1..)]
+        values: Vec<String>,
+        /// Additional set values
+        #[arg(short, long, num_args = 1..)]
+        set: Vec<String>,
+        /// Image tag - defaults to the git tag of the current commit
+        #[arg(long)]
+        image_tag: Option<String>,
+    },
+    /// Resolve chart versions and record them in the charts lock file
+    Resolve {
+        /// Charts to resolve - all charts if not given
+        charts: Vec<String>,
+        /// K8s environment to resolve - all environments if not given
+        #[arg(short, long)]
+        env: Option<String>,
+    },
+    /// Validate the charts configuration file
+    Validate {
+        /// Check that git repos are reachable
+        #[arg(long, action = clap::ArgAction::SetTrue)]
+        remote: Option<bool>,
+    },
+    /// Create a new chart, its charts config entry and the vars of each environment
+    New {
+        /// The name of the chart
+        name: String,
+        /// Template of the chart - `builtin` or a helm chart directory
+        #[arg(short, long, default_value = "builtin")]
+        template: String,
+        /// Directory to create the chart in - next to the charts config if not given
+        #[arg(short, long)]
+        path: Option<String>,
+        /// The namespace the chart is deployed in
+        #[arg(short, long)]
+        namespace: Option<String>,
+        /// Description of the chart
+        #[arg(short, long)]
+        description: Option<String>,
+        /// override additional variables path
+        #[arg(short, long)]
+        vars: Option<String>,
+        /// Add a block config routing `/<name>` to the chart service
+        #[arg(short, long, action = clap::ArgAction::SetTrue)]
+        block: Option<bool>,
+    },
+    /// Deploy a chart to the preview environment of the current branch
+    Preview {
+        /// The name of the chart
+        #[arg(required_unless_present = "destroy")]
+        chart: Option<String>,
+        /// K8s environment hosting previews - defaults to charts.preview_env
+        #[arg(short, long)]
+        env: Option<String>,
+        /// override additional variables path
+        #[arg(short, long)]
+        vars: Option<String>,
+        /// Local values files applied over the configured value layers
+        #[arg(short = 'f', long, num_args = 1..)]
+        values: Vec<String>,
+        /// Additional set values
+        #[arg(short, long, num_args = 1..)]
+        set: Vec<String>,
+        /// Wait for deployment to finish
+        #[arg(long, action = clap::ArgAction::SetTrue)]
+        wait: Option<bool>,
+        /// Tear down the preview environment of the branch
+        #[arg(long, action = clap::ArgAction::SetTrue)]
+        destroy: Option<bool>,
+        /// Dry run the preview
+        #[arg(long, action = clap::ArgAction::SetTrue)]
+        dry_run: Option<bool>,
+        /// Skip the confirmation of protected environments
+        #[arg(short, long, action = clap::ArgAction::SetTrue)]
+        yes: Option<bool>,
+    },
+    /// List preview environments and garbage collect stale ones
+    Previews {
+        /// K8s environment hosting previews - defaults to charts.preview_env
+        #[arg(short, long)]
+        env: Option<String>,
+        /// Delete previews of branches which no longer exist
+        #[arg(long, action = clap::ArgAction::SetTrue)]
+        gc: Option<bool>,
+        /// With --gc, also delete previews older than this number of days
+        #[arg(long)]
+        max_age: Option<u64>,
+        /// Dry run the garbage collection
+        #[arg(long, action = clap::ArgAction::SetTrue)]
+        dry_run: Option<bool>,
+    },
+    /// Show the deploy history of a release
+    History {
+        /// The name of the chart
+        chart: String,
+        /// K8s environment of the release
+        #[arg(short, long)]
+        env: Option<String>,
+        /// The namespace of the release
+        #[arg(short, long)]
+        namespace: Option<String>,
+        /// Maximum number of revisions to show
+        #[arg(long, default_value = "20")]
+        max: usize,
+        /// Output as JSON
+        #[arg(long, action = clap::ArgAction::SetTrue)]
+        json: Option<bool>,
+    },
+    /// Show the logs of the pods of a release
+    Logs {
+        /// The name of the chart
+        chart: String,
+        /// K8s environment of the release
+        #[arg(short, long)]
+        env: Option<String>,
+        /// The namespace of the release
+        #[arg(short, long)]
+        namespace: Option<String>,
+        /// Follow the logs
+        #[arg(short, long, action = clap::ArgAction::SetTrue)]
+        follow: Option<bool>,
+        /// Container to show logs of - all containers if not given
+        #[arg(short, long)]
+        container: Option<String>,
+        /// Number of recent lines to show of each container
+        #[arg(long)]
+        tail: Option<u64>,
+    },
+    /// Show the events of the workloads and pods of a release
+    Events {
+        /// The name of the chart
+        chart: String,
+        /// K8s environment of the release
+        #[arg(short, long)]
+        env: Option<String>,
+        /// The namespace of the release
+        #[arg(short, long)]
+        namespace: Option<String>,
+    },
+    /// Forward local ports to the service of a release, or its first ready pod
+    PortForward {
+        /// The name of the chart
+        chart: String,
+        /// Ports to forward as local:remote
+        #[arg(required = true)]
+        ports: Vec<String>,
+        /// K8s environment of the release
+        #[arg(short, long)]
+        env: Option<String>,
+        /// The namespace of the release
+        #[arg(short, long)]
+        namespace: Option<String>,
+    },
+    /// Run a command in the first ready pod of a release
+    Exec {
+        /// The name of the chart
+        chart: String,
+        /// Command to run, after --
+        #[arg(last = true, required = true)]
+        command: Vec<String>,
+        /// K8s environment of the release
+        #[arg(short, long)]
+        env: Option<String>,
+        /// The namespace of the release
+        #[arg(short, long)]
+        namespace: Option<String>,
+        /// Container to run the command in - the first container if not given
+        #[arg(short, long)]
+        container: Option<String>,
+    },
+    /// Lock a release to prevent deploys by others
+    Lock {
+        /// The name of the chart
+        chart: String,
+        /// K8s environment of the release
+        #[arg(short, long)]
+        env: Option<String>,
+        /// The namespace of the release
+        #[arg(short, long)]
+        namespace: Option<String>,
+        /// Reason for holding the lock
+        #[arg(short, long)]
+        message: Option<String>,
+        /// Break the lock if held by someone else
+        #[arg(long, action = clap::ArgAction::SetTrue)]
+        force: Option<bool>,
+    },
+    /// Release the deploy lock of a release
+    Unlock {
+        /// The name of the chart
+        chart: String,
+        /// K8s environment of the release
+        #[arg(short, long)]
+        env: Option<String>,
+        /// The namespace of the release
+        #[arg(short, long)]
+        namespace: Option<String>,
+        /// Release the lock if held by someone else
+        #[arg(long, action = clap::ArgAction::SetTrue)]
+        force: Option<bool>,
+    },
+    /// List deploy locks of an environment
+    Locks {
+        /// K8s environment to list locks for
+        #[arg(short, long)]
+        env: Option<String>,
+        /// List locks of a namespace only
+        #[arg(short, long)]
+        namespace: Option<String>,
+    },
+    /// Compare deployed releases with what would be deployed now
+    Drift {
+        /// K8s environment to check
+        #[arg(short, long)]
+        env: Option<String>,
+        /// override additional variables path
+        #[arg(short, long)]
+        vars: Option<String>,
+        /// Expected image tag - defaults to the git tag of the current commit
+        #[arg(long)]
+        image_tag: Option<String>,
+        /// Output as JSON
+        #[arg(long, action = clap::ArgAction::SetTrue)]
+        json: Option<bool>,
+    },
+    /// Uninstall a chart release
+    Uninstall {
+        /// The name of the chart
+        chart: String,
+        /// K8s environment to uninstall from
+        #[arg(short, long)]
+        env: Option<String>,
+        /// The namespace the chart is deployed in
+        #[arg(short, long)]
+        namespace: Option<String>,
+        /// Delete the associated block too
+        #[arg(short, long, action = clap::ArgAction::SetTrue)]
+        block: Option<bool>,
+        /// Wait for all resources to be deleted
+        #[arg(long, action = clap::ArgAction::SetTrue)]
+        wait: Option<bool>,
+        /// Dry run the uninstall
+        #[arg(long, action = clap::ArgAction::SetTrue)]
+        dry_run: Option<bool>,
+        /// Skip the confirmation of protected environments
+        #[arg(short, long, action = clap::ArgAction::SetTrue)]
+        yes: Option<bool>,
     },
 }
 
 #[derive(Debug, Clone, Deserialize, Serialize)]
 pub struct ChartsSettings {
-    /// mapping of environment to cluster names
+    /// mapping of environment to cluster names or environment tables
     #[serde(default)]
-    pub envs: HashMap<String, String>,
+    pub envs: HashMap<String, EnvConfig>,
+    /// environment used when none is given and prompts are not possible
+    pub default_env: Option<String>,
     /// location of the chart configuration yaml file
     #[serde(default = "ChartsSettings::get_default_chart_config")]
     pub config: String,
@@ -60,21 +397,298 @@ pub struct ChartsSettings {
     /// Default namespace
     #[serde(default = "ChartsSettings::get_default_namespace")]
     pub default_namespace: String,
+    /// audit trail of deploys
+    #[serde(default)]
+    pub audit: AuditSettings,
+    /// ordered list of value layers - later layers override earlier ones
+    ///
+    /// Defaults to the `{vars}/{env}` and `{vars}/{env}/{chart}` layers, other layers
+    /// such as `{vars}` or `{vars}/{chart}` have to be configured explicitly.
+    #[serde(default = "ChartsSettings::get_default_layers")]
+    pub layers: Vec<ValueLayer>,
+    /// environment hosting branch preview environments
+    pub preview_env: Option<String>,
+    /// minutes after which deploys refresh the index of a helm repo
+    #[serde(default = "ChartsSettings::get_default_helm_repo_ttl")]
+    pub helm_repo_ttl: u64,
+}
+
+/// An environment - a cluster name or a table with the cluster and its deploy policy
+#[derive(Debug, Default, Clone, Deserialize, Serialize)]
+#[serde(from = "EnvEntry")]
+pub struct EnvConfig {
+    pub cluster: String,
+    /// deploys require typing the environment name or passing --yes
+    #[serde(default)]
+    pub protected: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub description: Option<String>,
+    /// branches allowed to deploy, `*` matches any characters - any branch if empty
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub allowed_branches: Vec<String>,
+    /// deploys require no uncommitted changes
+    #[serde(default)]
+    pub require_clean_worktree: bool,
+    /// deploys require the current commit to be tagged
+    #[serde(default)]
+    pub require_tag: bool,
+}
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum EnvEntry {
+    Cluster(String),
+    Table {
+        cluster: String,
+        #[serde(default)]
+        protected: bool,
+        description: Option<String>,
+        #[serde(default)]
+        allowed_branches: Vec<String>,
+        #[serde(default)]
+        require_clean_worktree: bool,
+        #[serde(default)]
+        require_tag: bool,
+    },
+}
+
+impl From<EnvEntry> for EnvConfig {
+    fn from(entry: EnvEntry) -> Self {
+        match entry {
+            EnvEntry::Cluster(cluster) => Self {
+                cluster,
+                ..Default::default()
+            },
+            EnvEntry::Table {
+                cluster,
+                protected,
+                description,
+                allowed_branches,
+                require_clean_worktree,
+                require_tag,
+            } => Self {
+                cluster,
+                protected,
+                description,
+                allowed_branches,
+                require_clean_worktree,
+                require_tag,
+            },
+        }
+    }
+}
+
+impl EnvConfig {
+    /// Check the deploy policy of the environment against the git state
+    ///
+    /// Returns the violated rules with the reason
+    pub fn policy_violations(&self, git: &GitSettings) -> RopsResult<Vec<String>> {
+        let mut violations = vec![];
+        if !self.allowed_branches.is_empty()
+            && !self
+                .allowed_branches
+                .iter()
+                .any(|pattern| glob_match(pattern, &git.branch))
+        {
+            violations.push(format!(
+                "allowed_branches: branch '{}' is not one of {}",
+                git.branch,
+                self.allowed_branches.join(", ")
+            ));
+        }
+        if self.require_clean_worktree {
+            let changes = GitSettings::uncommitted_changes()?;
+            if !changes.is_empty() {
+                violations.push(format!(
+                    "require_clean_worktree: {} uncommitted changes ({})",
+                    changes.len(),
+                    changes
+                        .iter()
+                        .take(5)
+                        .cloned()
+                        .collect::<Vec<_>>()
+                        .join(", ")
+                ));
+            }
+        }
+        if self.require_tag && GitSettings::head_tags()?.is_empty() {
+            violations.push(format!(
+                "require_tag: commit {} is not tagged",
+                git.sha.chars().take(12).collect::<String>()
+            ));
+        }
+        Ok(violations)
+    }
+}
+
+/// A directory of values files applied to a chart deploy
+///
+/// The path can contain the `{vars}`, `{env}` and `{chart}` placeholders, layers
+/// using `{vars}` are skipped when no variables path is configured. The
+/// `values.yaml` and `secrets.yaml` files of the directory are used when they exist.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct ValueLayer {
+    pub name: String,
+    pub path: String,
 }
 
 #[derive(Debug, Default, Clone, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
 pub struct Chart {
+    /// helm chart reference, or the path of a kustomize overlay or manifests which
+    /// can contain the `{env}` placeholder
     pub chart: String,
+    /// how the chart is deployed - helm, kustomize or manifests
+    #[serde(default, rename = "type")]
+    pub chart_type: ChartType,
     pub alias: Option<String>,
     pub namespace: Option<String>,
     pub description: Option<String>,
     #[serde(default, rename = "helm-repos")]
     pub helm_repos: HashMap<String, String>,
     #[serde(default, rename = "git-repos")]
-    pub git_repos: HashMap<String, String>,
+    pub git_repos: HashMap<String, GitRepo>,
     pub block: Option<BlockConfig>,
     #[serde(default = "as_true", rename = "append-namespace")]
     pub append_namespace: bool,
+    /// chart version to deploy
+    pub version: Option<String>,
+    /// additional set values passed to helm
+    #[serde(default)]
+    pub set: Vec<String>,
+    /// additional arguments passed to helm
+    #[serde(default)]
+    pub args: Vec<String>,
+    /// post-deploy health check
+    pub health: Option<HealthCheck>,
+    /// commands executed around deploys
+    #[serde(default)]
+    pub hooks: ChartHooks,
+    /// mapping of helm value paths to rops images - the values are set to the image
+    /// tag, or to the repository and tag or digest, at deploy time
+    #[serde(default)]
+    pub images: BTreeMap<String, ChartImage>,
+    /// per-environment overrides merged over the chart definition
+    #[serde(default)]
+    pub envs: HashMap<String, ChartOverrides>,
+}
+
+/// An image injected into the chart values at deploy time
+///
+/// A rops image name sets the value path to the image tag. The mapping form treats the
+/// value path as the image values and sets their repository and tag keys, or the
+/// digest key when deploying a `sha256:` digest.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(untagged)]
+pub enum ChartImage {
+    Name(String),
+    Values(ImageValues),
+}
+
+/// Keys of the repository, tag and digest of an image in the chart values
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct ImageValues {
+    /// rops image name
+    pub name: String,
+    #[serde(default = "ImageValues::default_repository")]
+    pub repository: String,
+    #[serde(default = "ImageValues::default_tag")]
+    pub tag: String,
+    #[serde(default = "ImageValues::default_digest")]
+    pub digest: String,
+}
+
+/// Shell commands executed around a chart deploy
+///
+/// Commands run with `sh -c` and receive the deploy details as `ROPS_*`
+/// environment variables
+#[derive(Debug, Default, Clone, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct ChartHooks {
+    /// commands run before deploying - a failure aborts the deploy
+    #[serde(default, rename = "pre-deploy")]
+    pub pre_deploy: Vec<String>,
+    /// commands run after a successful deploy
+    #[serde(default, rename = "post-deploy")]
+    pub post_deploy: Vec<String>,
+    /// commands run when the deploy or a hook fails
+    #[serde(default, rename = "on-failure")]
+    pub on_failure: Vec<String>,
+}
+
+/// Environment specific overrides of a chart definition
+///
+/// Scalar values and the block replace the chart values, set values and
+/// arguments are appended to the chart ones
+#[derive(Debug, Default, Clone, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct ChartOverrides {
+    pub chart: Option<String>,
+    pub alias: Option<String>,
+    pub namespace: Option<String>,
+    pub version: Option<String>,
+    #[serde(default)]
+    pub set: Vec<String>,
+    #[serde(default)]
+    pub args: Vec<String>,
+    pub block: Option<BlockConfig>,
+    #[serde(rename = "append-namespace")]
+    pub append_namespace: Option<bool>,
+    #[serde(default)]
+    pub images: BTreeMap<String, ChartImage>,
+    pub health: Option<HealthCheck>,
+}
+
+impl ChartImage {
+    /// The rops image name
+    pub fn name(&self) -> &str {
+        match self {
+            Self::Name(name) => name,
+            Self::Values(values) => &values.name,
+        }
+    }
+
+    /// Helm set values of the image at a value path for a tag or digest reference
+    fn set_values(&self, path: &str, url: &str, reference: &str) -> Vec<String> {
+        match self {
+            Self::Name(_) => vec![format!("{path}={reference}")],
+            Self::Values(values) => {
+                let (reference_path, reference) = self.reference_value(path, reference);
+                vec![
+                    format!("{path}.{}={url}", values.repository),
+                    format!("{reference_path}={reference}"),
+                ]
+            }
+        }
+    }
+
+    /// Value path and value of the tag or digest reference of the image
+    fn reference_value(&self, path: &str, reference: &str) -> (String, String) {
+        match (self, image_digest(reference)) {
+            (Self::Name(_), _) => (path.to_string(), reference.to_string()),
+            (Self::Values(values), Some(digest)) => {
+                (format!("{path}.{}", values.digest), digest.to_string())
+            }
+            (Self::Values(values), None) => {
+                (format!("{path}.{}", values.tag), reference.to_string())
+            }
+        }
+    }
+}
+
+impl ImageValues {
+    fn default_repository() -> String {
+        "repository".to_string()
+    }
+
+    fn default_tag() -> String {
+        "tag".to_string()
+    }
+
+    fn default_digest() -> String {
+        "digest".to_string()
+    }
 }
 
 impl Default for ChartsSettings {
@@ -83,102 +697,757 @@ impl Default for ChartsSettings {
             config: Self::get_default_chart_config(),
             default_namespace: Self::get_default_namespace(),
             envs: HashMap::new(),
+            default_env: None,
             vars: None,
+            layers: Self::get_default_layers(),
+            audit: AuditSettings::default(),
+            preview_env: None,
+            helm_repo_ttl: Self::get_default_helm_repo_ttl(),
         }
     }
 }
 
+/// Chart versions resolved for each chart and environment
+///
+/// Stored next to the charts configuration file as `charts.lock`
+#[derive(Debug, Default, Clone, Deserialize, Serialize)]
+pub struct ChartsLock(BTreeMap<String, BTreeMap<String, LockedChart>>);
+
+#[derive(Debug, Default, Clone, Deserialize, Serialize)]
+pub struct LockedChart {
+    /// the chart reference the version was resolved for
+    pub chart: String,
+    /// the version constraint the version was resolved for
+    pub constraint: Option<String>,
+    /// the resolved chart version
+    pub version: String,
+}
+
+struct ValueFile {
+    path: std::path::PathBuf,
+    /// secrets files are decrypted with sops
+    secret: bool,
+}
+
+#[derive(Default)]
 pub struct DeployChart {
     chart: String,
+    env: String,
     config: Chart,
+    locked: Option<LockedChart>,
     cluster: String,
     namespace: String,
     wait: bool,
     dry_run: bool,
+    /// root of the variables path
     vars: Option<String>,
+    layers: Vec<ValueLayer>,
+    /// local values files applied over the value layers
+    values: Vec<String>,
     set: Vec<String>,
     args: Vec<String>,
+    /// images by helm value path with their repository url
+    images: BTreeMap<String, (ChartImage, String)>,
+    image_tag: String,
+    verify_images: bool,
+    git: GitSettings,
+    /// break the deploy lock if held by someone else
+    force: bool,
+    audit: AuditSettings,
+    helm_repo_ttl: Duration,
+}
+
+/// A revision of a release as reported by `helm history`
+#[derive(Debug, Default, Clone, Deserialize, Serialize)]
+pub struct Revision {
+    pub revision: u32,
+    #[serde(default)]
+    pub updated: String,
+    #[serde(default)]
+    pub status: String,
+    #[serde(default)]
+    pub chart: String,
+    #[serde(default)]
+    pub app_version: String,
+    #[serde(default)]
+    pub description: String,
 }
 
 impl ChartsCommand {
     /// Run the Docker command
     pub fn run(&self, settings: &Settings) -> RopsResult<()> {
-        let charts = serde_yaml::from_str::<HashMap<String, Chart>>(&std::fs::read_to_string(
-            &settings.charts.config,
-        )?)?;
+        // the charts config is created with the first new chart
+        let charts = match self {
+            Self::New { .. } if !Path::new(&settings.charts.config).exists() => HashMap::new(),
+            _ => settings.charts.load_charts()?,
+        };
         match self {
             Self::List => {
                 let json = serde_json::to_string_pretty(&charts)?;
                 println!("{}", json);
                 Ok(())
             }
-            Self::Update => {
-                ChartsSettings::install_helm_plugin(
-                    "secrets",
-                    "https://github.com/jkroepke/helm-secrets",
-                    None,
-                )?;
-                Ok(())
-            }
-            Self::Deploy {
+            Self::Values {
                 chart,
                 env,
                 namespace,
-                block,
                 vars,
+                values,
                 set,
-                args,
-                wait,
-                dry_run,
-            } => match charts.get(chart).cloned() {
-                Some(config) => {
-                    if !block.unwrap_or(false) {
-                        let namespace = if let Some(ns) = namespace.clone() {
-                            ns
-                        } else if let Some(ns) = config.namespace.clone() {
-                            ns
-                        } else {
-                            settings.charts.default_namespace.clone()
-                        };
-                        let env = env.clone().unwrap_or_else(|| "prod".to_string());
-                        let cluster = match settings.charts.envs.get(&env) {
-                            Some(cluster) => cluster.clone(),
-                            None => {
-                                return Err(RopsError::Error(format!(
-                                    "Environment '{env}' not found in charts settings - available are {}",
-                                    settings
-                                        .charts
-                                        .envs
-                                        .keys()
-                                        .cloned()
-                                        .collect::<Vec<_>>()
-                                        .join(", ")
-                                )));
-                            }
-                        };
-                        let vars = settings.charts.get_vars_path(env, vars.as_deref());
-                        let deploy_chart = DeployChart {
-                            chart: chart.clone(),
-                            config: config.clone(),
-                            namespace,
-                            cluster,
-                            vars,
-                            wait: wait.unwrap_or_default(),
-                            dry_run: dry_run.unwrap_or_default(),
-                            set: set.clone(),
-                            args: args.clone(),
-                        };
-                        deploy_chart.run()?;
-                    }
-                    if let Some(block_config) = config.block.as_ref() {
-                        let metablock = settings.blocks.metablock()?;
-                        metablock.apply(settings, block_config)?;
+                image_tag,
+            } => {
+                let env = settings.charts.resolve_env(env.as_deref())?;
+                let config = Self::get_chart(&charts, chart, &env)?;
+                let deploy_chart =
+                    DeployChart::new(settings, chart, &config, &env, namespace.as_deref())?;
+                let deploy_chart = DeployChart {
+                    vars: settings.charts.get_vars_root(vars.as_deref()),
+                    values: values.clone(),
+                    set: set.clone(),
+                    image_tag: image_tag.clone().unwrap_or(deploy_chart.image_tag.clone()),
+                    ..deploy_chart
+                };
+                // the chart and its repos are needed for the chart default values
+                deploy_chart.prepare()?;
+                print!("{}", serde_yaml::to_string(&deploy_chart.merged_values()?)?);
+                Ok(())
+            }
+            Self::Resolve { charts: names, env } => {
+                let mut names = if names.is_empty() {
+                    charts.keys().cloned().collect()
+                } else {
+                    names.clone()
+                };
+                names.sort();
+                let mut envs: Vec<String> = match env {
+                    Some(env) => vec![env.clone()],
+                    None => settings.charts.envs.keys().cloned().collect(),
+                };
+                envs.sort();
+                let mut lock = settings.charts.load_lock()?;
+                for name in names.iter() {
+                    for env in envs.iter() {
+                        let config = Self::get_chart(&charts, name, env)?;
+                        if config.is_local() || config.chart_type != ChartType::Helm {
+                            continue;
+                        }
+                        let deploy_chart = DeployChart::new(settings, name, &config, env, None)?;
+                        let locked = deploy_chart.resolve_version()?;
+                        log::info!(
+                            "Resolved chart '{name}' for '{env}' to {} version {}",
+                            locked.chart,
+                            locked.version
+                        );
+                        lock.insert(name, env, locked);
+                    }
+                }
+                settings.charts.save_lock(&lock)
+            }
+            Self::Validate { remote } => {
+                let mut names: Vec<_> = charts.keys().collect();
+                names.sort();
+                let content = fs::read_to_string(&settings.charts.config).unwrap_or_default();
+                let mut errors = vec![];
+                for name in names {
+                    for error in
+                        charts[name].validate(name, &settings.charts, remote.unwrap_or_default())
+                    {
+                        // locate the deepest key of the error path, e.g. `web.block.upstream`
+                        let path = error
+                            .strip_prefix(name.as_str())
+                            .and_then(|rest| rest.split_once(':'))
+                            .map(|(path, _)| path)
+                            .unwrap_or_default();
+                        let keys: Vec<&str> = std::iter::once(name.as_str())
+                            .chain(path.split('.').filter(|key| !key.is_empty()))
+                            .collect();
+                        let config = &settings.charts.config;
+                        errors.push(match key_location(&content, &keys) {
+                            Some((line, column)) => format!("{config}:{line}:{column}: {error}"),
+                            None => format!("{config}: {error}"),
+                        });
                     }
+                }
+                if errors.is_empty() {
+                    log::info!(
+                        "{} is valid - {} charts",
+                        settings.charts.config,
+                        charts.len()
+                    );
                     Ok(())
+                } else {
+                    for error in errors.iter() {
+                        log::error!("{error}");
+                    }
+                    Err(RopsError::Error(format!(
+                        "{} has {} errors",
+                        settings.charts.config,
+                        errors.len()
+                    )))
                 }
-                None => Err(RopsError::Error(format!("Chart '{}' not found", chart))),
-            },
+            }
+            Self::New {
+                name,
+                template,
+                path,
+                namespace,
+                description,
+                vars,
+                block,
+            } => {
+                if charts.contains_key(name) {
+                    return Err(RopsError::Error(format!(
+                        "Chart '{name}' already exists in {}",
+                        settings.charts.config
+                    )));
+                }
+                ChartScaffold::validate_name(name)?;
+                ChartScaffold::validate_template(template)?;
+                let vars = settings
+                    .charts
+                    .get_vars_root(vars.as_deref())
+                    .ok_or_else(|| {
+                        RopsError::Error(
+                        "Variables path not configured - set charts.vars in rops.toml or pass --vars"
+                            .into(),
+                    )
+                    })?;
+                let path = path.clone().map(PathBuf::from).unwrap_or_else(|| {
+                    Path::new(&settings.charts.config)
+                        .parent()
+                        .unwrap_or(Path::new(""))
+                        .join(name)
+                });
+                let scaffold = ChartScaffold {
+                    name: name.clone(),
+                    path,
+                    namespace: namespace.clone(),
+                    description: description.clone(),
+                    block: block.unwrap_or_default(),
+                };
+                let mut envs: Vec<String> = settings.charts.envs.keys().cloned().collect();
+                envs.sort();
+                // vars are kept if they exist, so they are created first to allow retries
+                scaffold.create_vars(&vars, &envs)?;
+                scaffold.create_chart(template)?;
+                scaffold.append_config(&settings.charts.config)
+            }
+            Self::Update => ToolsCommand::Update {
+                tool: "sops".to_string(),
+                version: None,
+            }
+            .run(settings),
+            Self::Repos(command) => command.run(&charts),
+            Self::Deploy {
+                chart,
+                env,
+                namespace,
+                block,
+                vars,
+                values,
+                set,
+                args,
+                wait,
+                dry_run,
+                image_tag,
+                verify_images,
+                force,
+                yes,
+            } => {
+                let chart = &Self::resolve_chart(&charts, chart.as_deref())?;
+                let env = settings.charts.resolve_env(env.as_deref())?;
+                let config = Self::get_chart(&charts, chart, &env)?;
+                settings.charts.authorize(
+                    &env,
+                    &format!("deploy '{chart}'"),
+                    &settings.git,
+                    dry_run.unwrap_or_default(),
+                    yes.unwrap_or_default(),
+                )?;
+                if !block.unwrap_or(false) {
+                    let deploy_chart =
+                        DeployChart::new(settings, chart, &config, &env, namespace.as_deref())?;
+                    let deploy_chart = DeployChart {
+                        vars: settings.charts.get_vars_root(vars.as_deref()),
+                        values: values.clone(),
+                        wait: wait.unwrap_or_default(),
+                        dry_run: dry_run.unwrap_or_default(),
+                        set: set.clone(),
+                        args: args.clone(),
+                        image_tag: image_tag.clone().unwrap_or(deploy_chart.image_tag.clone()),
+                        verify_images: verify_images.unwrap_or_default(),
+                        force: force.unwrap_or_default(),
+                        ..deploy_chart
+                    };
+                    let started = Instant::now();
+                    let result = deploy_chart.run();
+                    if !deploy_chart.dry_run {
+                        settings.notifications.notify(
+                            &deploy_chart
+                                .notification()
+                                .with_result(&result, started.elapsed()),
+                        );
+                    }
+                    result?;
+                }
+                if let Some(block_config) = config.block.as_ref() {
+                    let namespace = settings.charts.get_namespace(&config, namespace.as_deref());
+                    let block_config = block_config
+                        .for_release(&config.release_name(chart, &namespace), &namespace);
+                    let metablock = settings.blocks.metablock()?;
+                    metablock.apply(settings, &block_config)?;
+                }
+                Ok(())
+            }
+            Self::Template {
+                chart,
+                env,
+                namespace,
+                vars,
+                values,
+                set,
+                args,
+                output,
+                image_tag,
+            } => {
+                let env = settings.charts.resolve_env(env.as_deref())?;
+                let config = Self::get_chart(&charts, chart, &env)?;
+                let deploy_chart =
+                    DeployChart::new(settings, chart, &config, &env, namespace.as_deref())?;
+                let deploy_chart = DeployChart {
+                    vars: settings.charts.get_vars_root(vars.as_deref()),
+                    values: values.clone(),
+                    set: set.clone(),
+                    args: args.clone(),
+                    image_tag: image_tag.clone().unwrap_or(deploy_chart.image_tag.clone()),
+                    ..deploy_chart
+                };
+                deploy_chart.template(output.as_deref())
+            }
+            Self::Preview {
+                chart,
+                env,
+                vars,
+                values,
+                set,
+                wait,
+                destroy,
+                dry_run,
+                yes,
+            } => {
+                let env = settings.charts.get_preview_env(env.as_deref())?;
+                let dry_run = dry_run.unwrap_or_default();
+                if settings.git.is_default_branch() {
+                    return Err(RopsError::Error(format!(
+                        "Preview environments are not available for the default branch '{}'",
+                        settings.git.branch
+                    )));
+                }
+                let action = if destroy.unwrap_or_default() {
+                    "destroy the branch preview".to_string()
+                } else {
+                    format!("preview '{}'", chart.as_deref().unwrap_or_default())
+                };
+                settings.charts.authorize(
+                    &env,
+                    &action,
+                    &settings.git,
+                    dry_run,
+                    yes.unwrap_or_default(),
+                )?;
+                let mut preview = PreviewNamespace::new(&settings.git.branch);
+                let client = settings.charts.kube_client(&env)?;
+                if destroy.unwrap_or_default() {
+                    let preview = PreviewNamespace::get(&client, &preview.name)?.unwrap_or(preview);
+                    return Self::destroy_preview(settings, &client, &preview, dry_run);
+                }
+                let chart = chart.clone().unwrap_or_default();
+                let mut config = Self::get_chart(&charts, &chart, &env)?;
+                let namespace = settings.charts.get_namespace(&config, None);
+                let release = config.release_name(&chart, &preview.name);
+                if let Some(block_config) = config.block.as_mut() {
+                    *block_config = block_config.for_release(&release, &preview.name);
+                    block_config.name = format!("{}-{}", preview.subdomain(), block_config.name);
+                    block_config.upstream = preview.upstream(&block_config.upstream, &namespace);
+                    preview.blocks.push(format!(
+                        "{}/{}",
+                        block_config
+                            .space
+                            .as_deref()
+                            .unwrap_or(&settings.blocks.default_space),
+                        block_config.name
+                    ));
+                }
+                preview.apply(&client, dry_run)?;
+                let deploy_chart =
+                    DeployChart::new(settings, &chart, &config, &env, Some(&preview.name))?;
+                let deploy_chart = DeployChart {
+                    vars: settings.charts.get_vars_root(vars.as_deref()),
+                    values: values.clone(),
+                    wait: wait.unwrap_or_default(),
+                    dry_run,
+                    set: set.clone(),
+                    ..deploy_chart
+                };
+                deploy_chart.run()?;
+                if let Some(block_config) = config.block.as_ref() {
+                    if dry_run {
+                        log::info!(
+                            "Dry run mode enabled, skipping block '{}'",
+                            block_config.name
+                        );
+                    } else {
+                        settings.blocks.metablock()?.apply(settings, block_config)?;
+                    }
+                }
+                Ok(())
+            }
+            Self::Previews {
+                env,
+                gc,
+                max_age,
+                dry_run,
+            } => {
+                let env = settings.charts.get_preview_env(env.as_deref())?;
+                let client = settings.charts.kube_client(&env)?;
+                let previews = PreviewNamespace::list(&client)?;
+                println!("{:<50} {:<40} {:>5} STALE", "NAMESPACE", "BRANCH", "DAYS");
+                let mut stale = vec![];
+                for preview in previews {
+                    let is_stale = !preview.branch_exists()
+                        || max_age.is_some_and(|max_age| preview.age_days() > max_age);
+                    println!(
+                        "{:<50} {:<40} {:>5} {}",
+                        preview.name,
+                        preview.branch,
+                        preview.age_days(),
+                        if is_stale { "yes" } else { "no" }
+                    );
+                    if is_stale {
+                        stale.push(preview);
+                    }
+                }
+                if gc.unwrap_or_default() {
+                    for preview in stale.iter() {
+                        Self::destroy_preview(
+                            settings,
+                            &client,
+                            preview,
+                            dry_run.unwrap_or_default(),
+                        )?;
+                    }
+                }
+                Ok(())
+            }
+            Self::History {
+                chart,
+                env,
+                namespace,
+                max,
+                json,
+            } => {
+                let env = settings.charts.resolve_env(env.as_deref())?;
+                let config = Self::get_chart(&charts, chart, &env)?;
+                let deploy_chart =
+                    DeployChart::new(settings, chart, &config, &env, namespace.as_deref())?;
+                deploy_chart.fetch_cluster()?;
+                let history = deploy_chart.history(*max)?;
+                if json.unwrap_or_default() {
+                    println!("{}", serde_json::to_string_pretty(&history)?);
+                } else {
+                    println!(
+                        "{:<9} {:<20} {:<12} {:<30} DESCRIPTION",
+                        "REVISION", "UPDATED", "STATUS", "CHART"
+                    );
+                    for revision in history {
+                        println!(
+                            "{:<9} {:<20} {:<12} {:<30} {}",
+                            revision.revision,
+                            revision.updated.chars().take(19).collect::<String>(),
+                            revision.status,
+                            revision.chart,
+                            revision.description
+                        );
+                    }
+                }
+                Ok(())
+            }
+            Self::Logs {
+                chart,
+                env,
+                namespace,
+                follow,
+                container,
+                tail,
+            } => {
+                let env = settings.charts.resolve_env(env.as_deref())?;
+                let config = Self::get_chart(&charts, chart, &env)?;
+                let deploy_chart =
+                    DeployChart::new(settings, chart, &config, &env, namespace.as_deref())?;
+                deploy_chart.fetch_cluster()?;
+                deploy_chart.logs(container.as_deref(), follow.unwrap_or_default(), *tail)
+            }
+            Self::Events {
+                chart,
+                env,
+                namespace,
+            } => {
+                let env = settings.charts.resolve_env(env.as_deref())?;
+                let config = Self::get_chart(&charts, chart, &env)?;
+                let deploy_chart =
+                    DeployChart::new(settings, chart, &config, &env, namespace.as_deref())?;
+                deploy_chart.fetch_cluster()?;
+                let events = deploy_chart.events()?;
+                if events.is_empty() {
+                    println!("No events of release '{}'", deploy_chart.release_name());
+                    return Ok(());
+                }
+                println!(
+                    "{:<20} {:<8} {:<20} {:<40} MESSAGE",
+                    "LAST SEEN", "TYPE", "REASON", "OBJECT"
+                );
+                for event in events {
+                    println!(
+                        "{:<20} {:<8} {:<20} {:<40} {}{}",
+                        event.last_seen.chars().take(19).collect::<String>(),
+                        event.event_type,
+                        event.reason,
+                        format!("{}/{}", event.kind.to_lowercase(), event.name),
+                        event.message,
+                        if event.count > 1 {
+                            format!(" (x{})", event.count)
+                        } else {
+                            String::new()
+                        }
+                    );
+                }
+                Ok(())
+            }
+            Self::PortForward {
+                chart,
+                ports,
+                env,
+                namespace,
+            } => {
+                let env = settings.charts.resolve_env(env.as_deref())?;
+                let config = Self::get_chart(&charts, chart, &env)?;
+                let deploy_chart =
+                    DeployChart::new(settings, chart, &config, &env, namespace.as_deref())?;
+                deploy_chart.fetch_cluster()?;
+                deploy_chart.port_forward(ports)
+            }
+            Self::Exec {
+                chart,
+                command,
+                env,
+                namespace,
+                container,
+            } => {
+                let env = settings.charts.resolve_env(env.as_deref())?;
+                let config = Self::get_chart(&charts, chart, &env)?;
+                let deploy_chart =
+                    DeployChart::new(settings, chart, &config, &env, namespace.as_deref())?;
+                deploy_chart.fetch_cluster()?;
+                deploy_chart.exec(container.as_deref(), command)
+            }
+            Self::Lock {
+                chart,
+                env,
+                namespace,
+                message,
+                force,
+            } => {
+                let env = settings.charts.resolve_env(env.as_deref())?;
+                let config = Self::get_chart(&charts, chart, &env)?;
+                let deploy_chart =
+                    DeployChart::new(settings, chart, &config, &env, namespace.as_deref())?;
+                deploy_chart.fetch_cluster()?;
+                let info = LockInfo {
+                    message: Some(message.clone().unwrap_or_else(|| "held".to_string())),
+                    token: None,
+                    ..deploy_chart.lock_info()
+                };
+                deploy_chart
+                    .deploy_lock()
+                    .acquire(&info, force.unwrap_or_default())?;
+                Ok(())
+            }
+            Self::Unlock {
+                chart,
+                env,
+                namespace,
+                force,
+            } => {
+                let env = settings.charts.resolve_env(env.as_deref())?;
+                let config = Self::get_chart(&charts, chart, &env)?;
+                let deploy_chart =
+                    DeployChart::new(settings, chart, &config, &env, namespace.as_deref())?;
+                deploy_chart.fetch_cluster()?;
+                let lock = deploy_chart.deploy_lock();
+                match lock.get()? {
+                    None => {
+                        log::info!("Release '{}' is not locked", deploy_chart.release_name());
+                        Ok(())
+                    }
+                    Some(existing)
+                        if existing.owner != deploy_chart.lock_info().owner
+                            && !force.unwrap_or_default() =>
+                    {
+                        Err(RopsError::Error(format!(
+                            "Release '{}' is locked by {existing} - use --force to release it",
+                            deploy_chart.release_name()
+                        )))
+                    }
+                    Some(_) => lock.release(),
+                }
+            }
+            Self::Locks { env, namespace } => {
+                let env = settings.charts.resolve_env(env.as_deref())?;
+                let cluster = settings.charts.get_cluster(&env)?;
+                ChartsSettings::fetch_cluster(&cluster, false)?;
+                let locks = DeployLock::list(namespace.as_deref())?;
+                if locks.is_empty() {
+                    println!("No deploy locks in '{env}'");
+                }
+                for (namespace, info) in locks {
+                    println!("{namespace}/{}: {info}", info.release);
+                }
+                Ok(())
+            }
+            Self::Drift {
+                env,
+                vars,
+                image_tag,
+                json,
+            } => {
+                let env = settings.charts.resolve_env(env.as_deref())?;
+                let cluster = settings.charts.get_cluster(&env)?;
+                ChartsSettings::fetch_cluster(&cluster, false)?;
+                let mut names: Vec<_> = charts.keys().collect();
+                names.sort();
+                let mut drifts = vec![];
+                for name in names {
+                    let config = Self::get_chart(&charts, name, &env)?;
+                    // a failing chart, e.g. with undecryptable secrets, is reported on its own
+                    let drift = DeployChart::new(settings, name, &config, &env, None)
+                        .and_then(|deploy_chart| {
+                            DeployChart {
+                                vars: settings.charts.get_vars_root(vars.as_deref()),
+                                image_tag: image_tag
+                                    .clone()
+                                    .unwrap_or(deploy_chart.image_tag.clone()),
+                                ..deploy_chart
+                            }
+                            .drift()
+                        })
+                        .unwrap_or_else(|err| {
+                            log::error!("Failed to check drift of chart '{name}': {err}");
+                            Drift::error(
+                                name,
+                                &env,
+                                &settings.charts.get_namespace(&config, None),
+                                &err,
+                            )
+                        });
+                    drifts.push(drift);
+                }
+                if json.unwrap_or_default() {
+                    println!("{}", serde_json::to_string_pretty(&drifts)?);
+                } else {
+                    Drift::print_table(&drifts);
+                }
+                let drifted = drifts.iter().filter(|drift| drift.drifted()).count();
+                if drifted == 0 {
+                    Ok(())
+                } else {
+                    Err(RopsError::Error(format!(
+                        "{drifted} of {} charts drifted in '{env}'",
+                        drifts.len()
+                    )))
+                }
+            }
+            Self::Uninstall {
+                chart,
+                env,
+                namespace,
+                block,
+                wait,
+                dry_run,
+                yes,
+            } => {
+                let env = settings.charts.resolve_env(env.as_deref())?;
+                let config = Self::get_chart(&charts, chart, &env)?;
+                let dry_run = dry_run.unwrap_or_default();
+                settings.charts.authorize(
+                    &env,
+                    &format!("uninstall '{chart}'"),
+                    &settings.git,
+                    dry_run,
+                    yes.unwrap_or_default(),
+                )?;
+                let deploy_chart = DeployChart {
+                    wait: wait.unwrap_or_default(),
+                    dry_run,
+                    ..DeployChart::new(settings, chart, &config, &env, namespace.as_deref())?
+                };
+                deploy_chart.uninstall()?;
+                if block.unwrap_or(false)
+                    && let Some(block_config) = config.block.as_ref()
+                {
+                    let metablock = settings.blocks.metablock()?;
+                    metablock.remove(settings, block_config, dry_run)?;
+                }
+                Ok(())
+            }
+        }
+    }
+
+    /// Remove the blocks of a preview environment and delete its namespace
+    fn destroy_preview(
+        settings: &Settings,
+        client: &KubeClient,
+        preview: &PreviewNamespace,
+        dry_run: bool,
+    ) -> RopsResult<()> {
+        if !preview.blocks.is_empty() {
+            let metablock = settings.blocks.metablock()?;
+            for block in preview.blocks.iter() {
+                let (space, name) = block.split_once('/').unwrap_or(("", block));
+                let block_config = BlockConfig {
+                    name: name.to_string(),
+                    space: Some(space.to_string()).filter(|space| !space.is_empty()),
+                    ..Default::default()
+                };
+                metablock.remove(settings, &block_config, dry_run)?;
+            }
+        }
+        preview.delete(client, dry_run)?;
+        log::info!("Preview environment '{}' destroyed", preview.name);
+        Ok(())
+    }
+
+    /// The chart to act on - picked interactively when not given
+    fn resolve_chart(charts: &HashMap<String, Chart>, chart: Option<&str>) -> RopsResult<String> {
+        if let Some(chart) = chart {
+            return Ok(chart.to_string());
         }
+        if !prompt::is_interactive() {
+            return Err(RopsError::Error("No chart given".into()));
+        }
+        let mut items: Vec<_> = charts
+            .iter()
+            .map(|(name, config)| (name.clone(), config.description.clone()))
+            .collect();
+        items.sort();
+        prompt::select("Chart", &items, None)
+    }
+
+    /// Get a chart configuration with the environment overrides applied
+    fn get_chart(charts: &HashMap<String, Chart>, chart: &str, env: &str) -> RopsResult<Chart> {
+        charts
+            .get(chart)
+            .map(|config| config.for_env(env))
+            .ok_or_else(|| RopsError::Error(format!("Chart '{}' not found", chart)))
     }
 }
 
@@ -191,93 +1460,1042 @@ impl ChartsSettings {
         std::env::var("CHARTS_DEFAULT_NAMESPACE").unwrap_or_else(|_| "services".to_string())
     }
 
-    pub fn install_helm_plugin(name: &str, repo: &str, action: Option<&str>) -> RopsResult<()> {
-        let action = action.unwrap_or("install");
-        let mut command = Command::new("helm");
-        command.arg("plugin").arg(action).arg(repo);
-        if StreamCommand::new(command).run()? {
+    pub fn get_default_helm_repo_ttl() -> u64 {
+        60
+    }
+
+    /// Load and parse the charts configuration file
+    pub fn load_charts(&self) -> RopsResult<HashMap<String, Chart>> {
+        let content = fs::read_to_string(&self.config).map_err(|err| {
+            RopsError::Error(format!(
+                "Failed to read charts config '{}': {err}",
+                self.config
+            ))
+        })?;
+        serde_yaml::from_str(&content).map_err(|err| match err.location() {
+            Some(location) => RopsError::Error(format!(
+                "{}:{}:{}: {err}",
+                self.config,
+                location.line(),
+                location.column()
+            )),
+            None => RopsError::Error(format!("{}: {err}", self.config)),
+        })
+    }
+
+    /// The environment hosting preview environments
+    pub fn get_preview_env(&self, env: Option<&str>) -> RopsResult<String> {
+        env.map(str::to_string)
+            .or_else(|| self.preview_env.clone())
+            .ok_or_else(|| {
+                RopsError::Error(
+                    "Preview environment not configured - set charts.preview_env in rops.toml or pass --env"
+                        .into(),
+                )
+            })
+    }
+
+    /// Update the kubeconfig with the credentials of a cluster
+    pub fn fetch_cluster(cluster: &str, dry_run: bool) -> RopsResult<()> {
+        let mut command = Command::new("aws");
+        command
+            .arg("eks")
+            .arg("update-kubeconfig")
+            .arg("--name")
+            .arg(cluster);
+        if StreamCommand::new(command).with_dry_run(dry_run).run()? {
             Ok(())
-        } else if action == "install" {
-            Self::install_helm_plugin(name, name, Some("update"))
         } else {
             Err(RopsError::Error(format!(
-                "Failed to {action} Helm plugin '{repo}'"
+                "Failed to update kubeconfig for cluster '{}'",
+                cluster
             )))
         }
     }
 
-    pub fn get_vars_path(&self, env: String, vars: Option<&str>) -> Option<String> {
+    /// API client of the cluster of an environment
+    ///
+    /// The kubeconfig of the cluster is fetched first, also in dry runs, so that the
+    /// client never uses whatever context happens to be current.
+    pub fn kube_client(&self, env: &str) -> RopsResult<KubeClient> {
+        Self::fetch_cluster(&self.get_cluster(env)?, false)?;
+        KubeClient::from_kubeconfig()
+    }
+
+    /// Location of the charts lock file - next to the charts configuration file
+    pub fn lock_path(&self) -> std::path::PathBuf {
+        Path::new(&self.config).with_file_name("charts.lock")
+    }
+
+    /// Load the charts lock file, empty if it does not exist
+    pub fn load_lock(&self) -> RopsResult<ChartsLock> {
+        let path = self.lock_path();
+        if path.exists() {
+            Ok(serde_yaml::from_str(&fs::read_to_string(&path)?)?)
+        } else {
+            Ok(ChartsLock::default())
+        }
+    }
+
+    pub fn save_lock(&self, lock: &ChartsLock) -> RopsResult<()> {
+        let path = self.lock_path();
+        fs::write(&path, serde_yaml::to_string(lock)?)?;
+        log::info!("Charts lock written to {}", path.display());
+        Ok(())
+    }
+
+    pub fn get_cluster(&self, env: &str) -> RopsResult<String> {
+        match self.envs.get(env) {
+            Some(config) => Ok(config.cluster.clone()),
+            None => Err(RopsError::Error(format!(
+                "Environment '{env}' not found in charts settings - available are {}",
+                self.envs.keys().cloned().collect::<Vec<_>>().join(", ")
+            ))),
+        }
+    }
+
+    /// The environment to use when `--env` is not given
+    ///
+    /// Prompts for it on a terminal, otherwise falls back to the `default_env` setting
+    pub fn resolve_env(&self, env: Option<&str>) -> RopsResult<String> {
+        if let Some(env) = env {
+            return Ok(env.to_string());
+        }
+        if prompt::is_interactive() && !self.envs.is_empty() {
+            let mut envs: Vec<_> = self
+                .envs
+                .iter()
+                .map(|(name, config)| {
+                    let mut description = config.description.clone().unwrap_or_default();
+                    if config.protected {
+                        description = format!("[protected] {description}");
+                    }
+                    (name.clone(), Some(description).filter(|d| !d.is_empty()))
+                })
+                .collect();
+            envs.sort();
+            return prompt::select("Environment", &envs, self.default_env.as_deref());
+        }
+        self.default_env.clone().ok_or_else(|| {
+            let mut envs: Vec<_> = self.envs.keys().cloned().collect();
+            envs.sort();
+            RopsError::Error(format!(
+                "No environment given - pass --env or set charts.default_env in rops.toml, available are {}",
+                envs.join(", ")
+            ))
+        })
+    }
+
+    /// Enforce the deploy policy and protection of an environment before changing
+    /// releases in it - every deploy, preview and uninstall goes through this check
+    pub fn authorize(
+        &self,
+        env: &str,
+        action: &str,
+        git: &GitSettings,
+        dry_run: bool,
+        yes: bool,
+    ) -> RopsResult<()> {
+        self.check_policy(env, action, git, dry_run)?;
+        if dry_run {
+            Ok(())
+        } else {
+            self.confirm_protected(env, action, yes)
+        }
+    }
+
+    /// Enforce the deploy policy of an environment - violations are only logged in dry runs
+    fn check_policy(
+        &self,
+        env: &str,
+        action: &str,
+        git: &GitSettings,
+        dry_run: bool,
+    ) -> RopsResult<()> {
+        let Some(config) = self.envs.get(env) else {
+            return Ok(());
+        };
+        let violations = config.policy_violations(git)?;
+        if violations.is_empty() {
+            return Ok(());
+        }
+        if dry_run {
+            for violation in violations.iter() {
+                log::warn!("Can not {action} in '{env}' - blocked by {violation}");
+            }
+            return Ok(());
+        }
+        Err(RopsError::Error(format!(
+            "Can not {action} in '{env}' - blocked by the environment policy: {}",
+            violations.join("; ")
+        )))
+    }
+
+    /// Require a confirmation to act on a protected environment
+    ///
+    /// The environment name must be typed on a terminal, otherwise `--yes` is required
+    fn confirm_protected(&self, env: &str, action: &str, yes: bool) -> RopsResult<()> {
+        if yes || !self.envs.get(env).is_some_and(|config| config.protected) {
+            return Ok(());
+        }
+        if !prompt::is_interactive() {
+            return Err(RopsError::Error(format!(
+                "Environment '{env}' is protected - pass --yes to {action} without a prompt"
+            )));
+        }
+        log::warn!("Environment '{env}' is protected");
+        if prompt::confirm_typed(&format!("Type '{env}' to {action}"), env)? {
+            Ok(())
+        } else {
+            Err(RopsError::Error(format!(
+                "Confirmation did not match '{env}' - aborted"
+            )))
+        }
+    }
+
+    /// The namespace for a chart - the command line namespace takes precedence over
+    /// the chart namespace, which takes precedence over the default namespace
+    pub fn get_namespace(&self, config: &Chart, namespace: Option<&str>) -> String {
+        namespace
+            .map(str::to_string)
+            .or_else(|| config.namespace.clone())
+            .unwrap_or_else(|| self.default_namespace.clone())
+    }
+
+    pub fn get_default_layers() -> Vec<ValueLayer> {
+        [
+            ("env", "{vars}/{env}"),
+            ("chart-env", "{vars}/{env}/{chart}"),
+        ]
+        .into_iter()
+        .map(|(name, path)| ValueLayer {
+            name: name.to_string(),
+            path: path.to_string(),
+        })
+        .collect()
+    }
+
+    /// The variables path - the command line path takes precedence over the settings
+    pub fn get_vars_root(&self, vars: Option<&str>) -> Option<String> {
         let vars = if vars.is_some() {
             vars
         } else {
             self.vars.as_deref()
         };
         vars.map(|path| {
-            let path = fs::canonicalize(path)
+            fs::canonicalize(path)
                 .unwrap_or_else(|_| path.into())
                 .to_string_lossy()
-                .to_string();
-            format!("{path}/{env}")
+                .to_string()
         })
     }
+
+    pub fn get_vars_path(&self, env: String, vars: Option<&str>) -> Option<String> {
+        self.get_vars_root(vars).map(|path| format!("{path}/{env}"))
+    }
+}
+
+impl ChartsLock {
+    pub fn get(&self, chart: &str, env: &str) -> Option<&LockedChart> {
+        self.0.get(chart).and_then(|envs| envs.get(env))
+    }
+
+    pub fn insert(&mut self, chart: &str, env: &str, locked: LockedChart) {
+        self.0
+            .entry(chart.to_string())
+            .or_default()
+            .insert(env.to_string(), locked);
+    }
+}
+
+impl LockedChart {
+    /// Check if the lock entry was resolved for a chart definition
+    pub fn matches(&self, config: &Chart) -> bool {
+        self.chart == config.chart && self.constraint == config.version
+    }
+}
+
+impl Chart {
+    /// Check if the chart is an OCI chart reference
+    pub fn is_oci(&self) -> bool {
+        self.chart.starts_with("oci://")
+    }
+
+    /// Release name of the chart in a namespace - the alias or chart name, with the
+    /// namespace appended unless `append-namespace` is false
+    pub fn release_name(&self, chart: &str, namespace: &str) -> String {
+        let name_or_alias = self.alias.as_deref().unwrap_or(chart);
+        if self.append_namespace {
+            format!("{name_or_alias}-{namespace}")
+        } else {
+            name_or_alias.to_string()
+        }
+    }
+
+    /// Check if the chart is a local chart directory
+    ///
+    /// Charts of a configured helm repo, `repo/chart`, and chart urls are never local,
+    /// even when a directory with the same path exists
+    pub fn is_local(&self) -> bool {
+        if self.is_oci() || self.chart.contains("://") {
+            return false;
+        }
+        if let Some((repo, _)) = self.chart.split_once('/')
+            && self.helm_repos.contains_key(repo)
+        {
+            return false;
+        }
+        self.chart.starts_with('.')
+            || self.chart.starts_with('/')
+            || Path::new(&self.chart).is_dir()
+    }
+
+    /// Return the chart definition with the overrides of an environment merged in
+    pub fn for_env(&self, env: &str) -> Self {
+        let mut chart = self.clone();
+        if let Some(overrides) = self.envs.get(env) {
+            if let Some(value) = overrides.chart.as_ref() {
+                chart.chart = value.clone();
+            }
+            if overrides.alias.is_some() {
+                chart.alias = overrides.alias.clone();
+            }
+            if overrides.namespace.is_some() {
+                chart.namespace = overrides.namespace.clone();
+            }
+            if overrides.version.is_some() {
+                chart.version = overrides.version.clone();
+            }
+            if overrides.block.is_some() {
+                chart.block = overrides.block.clone();
+            }
+            if overrides.health.is_some() {
+                chart.health = overrides.health.clone();
+            }
+            if let Some(value) = overrides.append_namespace {
+                chart.append_namespace = value;
+            }
+            chart.set.extend(overrides.set.iter().cloned());
+            chart.args.extend(overrides.args.iter().cloned());
+            chart.images.extend(overrides.images.clone());
+        }
+        chart
+    }
+
+    /// Validate the chart configuration and return a list of errors
+    pub fn validate(&self, name: &str, settings: &ChartsSettings, remote: bool) -> Vec<String> {
+        let mut errors = vec![];
+        if self.chart.is_empty() {
+            errors.push(format!("{name}.chart: must not be empty"));
+        }
+        if let Some(version) = self.version.as_ref()
+            && let Err(err) = VersionReq::parse(version)
+        {
+            errors.push(format!(
+                "{name}.version: invalid version '{version}' - {err}"
+            ));
+        }
+        if self.chart_type != ChartType::Helm {
+            if self.version.is_some() {
+                errors.push(format!("{name}.version: only supported by helm charts"));
+            }
+            if !self.helm_repos.is_empty() {
+                errors.push(format!("{name}.helm-repos: only supported by helm charts"));
+            }
+        }
+        for (repo_name, repo_url) in self.helm_repos.iter() {
+            match Url::parse(repo_url) {
+                Ok(url) if ["http", "https", "oci"].contains(&url.scheme()) => {}
+                Ok(url) => errors.push(format!(
+                    "{name}.helm-repos.{repo_name}: unsupported scheme '{}' in '{repo_url}'",
+                    url.scheme()
+                )),
+                Err(err) => errors.push(format!(
+                    "{name}.helm-repos.{repo_name}: invalid url '{repo_url}' - {err}"
+                )),
+            }
+        }
+        for (repo_name, repo) in self.git_repos.iter() {
+            let repo_url = &repo.url;
+            if !GitSettings::is_repo_url(repo_url) {
+                errors.push(format!(
+                    "{name}.git-repos.{repo_name}: invalid git url '{repo_url}'"
+                ));
+            } else if remote && !GitSettings::is_reachable(repo_url) {
+                errors.push(format!(
+                    "{name}.git-repos.{repo_name}: git repo '{repo_url}' is not reachable"
+                ));
+            }
+        }
+        if let Some(block) = self.block.as_ref() {
+            errors.extend(block.validate(&format!("{name}.block")));
+        }
+        for (env, overrides) in self.envs.iter() {
+            if !settings.envs.contains_key(env) {
+                errors.push(format!(
+                    "{name}.envs.{env}: environment not found in charts settings"
+                ));
+            }
+            if let Some(version) = overrides.version.as_ref()
+                && let Err(err) = VersionReq::parse(version)
+            {
+                errors.push(format!(
+                    "{name}.envs.{env}.version: invalid version '{version}' - {err}"
+                ));
+            }
+            if let Some(block) = overrides.block.as_ref() {
+                errors.extend(block.validate(&format!("{name}.envs.{env}.block")));
+            }
+        }
+        errors
+    }
 }
 
 impl DeployChart {
+    pub fn new(
+        settings: &Settings,
+        chart: &str,
+        config: &Chart,
+        env: &str,
+        namespace: Option<&str>,
+    ) -> RopsResult<Self> {
+        let locked = settings
+            .charts
+            .load_lock()?
+            .get(chart, env)
+            .filter(|locked| locked.matches(config))
+            .cloned();
+        Ok(Self {
+            chart: chart.to_string(),
+            env: env.to_string(),
+            config: config.clone(),
+            locked,
+            cluster: settings.charts.get_cluster(env)?,
+            namespace: settings.charts.get_namespace(config, namespace),
+            wait: false,
+            dry_run: false,
+            vars: None,
+            layers: settings.charts.layers.clone(),
+            values: vec![],
+            set: vec![],
+            args: vec![],
+            images: config
+                .images
+                .iter()
+                .map(|(path, image)| {
+                    let url = settings.get_repo_url(image.name());
+                    (path.clone(), (image.clone(), url))
+                })
+                .collect(),
+            image_tag: settings.get_git_tag(),
+            verify_images: false,
+            git: settings.git.clone(),
+            force: false,
+            audit: settings.charts.audit.clone(),
+            helm_repo_ttl: Duration::from_secs(settings.charts.helm_repo_ttl * 60),
+        })
+    }
+
+    /// The helm release name of the chart
+    pub fn release_name(&self) -> String {
+        self.config.release_name(&self.chart, &self.namespace)
+    }
+
     pub fn run(&self) -> RopsResult<()> {
-        // Clone git repos if they are specified
-        for (repo_name, repo) in self.config.git_repos.iter() {
-            GitSettings::clone_repo(repo_name, repo)?;
+        if self.verify_images {
+            self.verify_images()?;
         }
-        // Add helm repos if they are specified
-        for (repo_name, repo) in self.config.helm_repos.iter() {
-            self.add_helm_repo(repo_name, repo)?;
-        }
-        let mut command = Command::new("helm");
-        //
-        // if vars are given use helm secrets
-        if self.vars.is_some() {
-            command.env("DECRYPT_CHARTS", "true").arg("secrets");
-        }
-        let name_or_alias = self.config.alias.as_deref().unwrap_or(self.chart.as_str());
-        let chart_name = if self.config.append_namespace {
-            format!("{name_or_alias}-{}", self.namespace)
+        self.prepare()?;
+        self.fetch_cluster()?;
+        let lock = if self.dry_run {
+            None
         } else {
-            name_or_alias.to_string()
+            let lock = self.deploy_lock();
+            // the lock lives in the namespace of the release
+            lock.ensure_namespace()?;
+            match lock.acquire(&self.lock_info(), self.force)? {
+                Acquired::Created => Some(lock),
+                Acquired::Held => None,
+            }
         };
+        let result = self.upgrade_with_hooks();
+        if !self.dry_run {
+            self.audit.record(&AuditRecord {
+                success: result.is_ok(),
+                error: result.as_ref().err().map(|err| err.to_string()),
+                ..self.audit_record()
+            });
+        }
+        if let Some(lock) = lock {
+            match (lock.release(), &result) {
+                (Err(err), Ok(())) => return Err(err),
+                (Err(err), Err(_)) => log::error!("{err}"),
+                _ => {}
+            }
+        }
+        result
+    }
+
+    /// Upgrade the release between the pre and post deploy hooks, running the
+    /// failure hooks if any of them fails
+    fn upgrade_with_hooks(&self) -> RopsResult<()> {
+        let hooks = &self.config.hooks;
+        let result = self
+            .run_hooks("pre-deploy", &hooks.pre_deploy, None)
+            .and_then(|_| self.upgrade())
+            .and_then(|_| self.run_hooks("post-deploy", &hooks.post_deploy, None));
+        if let Err(err) = result.as_ref()
+            && let Err(hook_err) = self.run_hooks("on-failure", &hooks.on_failure, Some(err))
+        {
+            log::error!("{hook_err}");
+        }
+        result
+    }
+
+    /// Run hook commands in order, stopping at the first failure
+    fn run_hooks(
+        &self,
+        hook: &str,
+        commands: &[String],
+        error: Option<&RopsError>,
+    ) -> RopsResult<()> {
+        for hook_command in commands {
+            log::info!("Running {hook} hook of chart '{}'", self.chart);
+            let mut command = Command::new("sh");
+            command
+                .arg("-c")
+                .arg(hook_command)
+                .env("ROPS_HOOK", hook)
+                .env("ROPS_CHART", &self.chart)
+                .env("ROPS_ENV", &self.env)
+                .env("ROPS_NAMESPACE", &self.namespace)
+                .env("ROPS_CLUSTER", &self.cluster)
+                .env("ROPS_RELEASE", self.release_name())
+                .env("ROPS_IMAGE_TAG", &self.image_tag)
+                .env("ROPS_GIT_SHA", &self.git.sha)
+                .env("ROPS_GIT_BRANCH", &self.git.branch);
+            if let Some(error) = error {
+                command.env("ROPS_ERROR", error.to_string());
+            }
+            if !StreamCommand::new(command)
+                .with_dry_run(self.dry_run)
+                .with_strict(true)
+                .run()?
+            {
+                return Err(RopsError::Error(format!(
+                    "{hook} hook of chart '{}' failed: {hook_command}",
+                    self.chart
+                )));
+            }
+        }
+        Ok(())
+    }
+
+    /// Run helm upgrade, or apply the manifests, and verify the health of the release
+    fn upgrade(&self) -> RopsResult<()> {
+        let chart_name = self.release_name();
+        if self.config.chart_type != ChartType::Helm {
+            self.manifests()?.apply(
+                &self.kube_client()?,
+                &chart_name,
+                &self.namespace,
+                self.dry_run,
+            )?;
+            return match self.config.health.as_ref() {
+                Some(health) if !self.dry_run => self.check_health(health),
+                _ => Ok(()),
+            };
+        }
+        let (mut command, _decrypted) = self.helm_command("upgrade")?;
         command
-            .arg("upgrade")
-            .arg(&chart_name)
-            .arg(&self.config.chart)
             .arg("--install")
+            .arg("--description")
+            .arg(self.audit_record().description());
+        if self.wait {
+            command.arg("--wait");
+        }
+        if !StreamCommand::new(command)
+            .with_dry_run(self.dry_run)
+            .run()?
+        {
+            return Err(RopsError::Error(format!(
+                "Failed to deploy Helm repo '{}'",
+                chart_name
+            )));
+        }
+        match self.config.health.as_ref() {
+            Some(health) if !self.dry_run => self.check_health(health),
+            _ => Ok(()),
+        }
+    }
+
+    /// Audit record of a deploy of the release by the current user
+    pub fn audit_record(&self) -> AuditRecord {
+        AuditRecord {
+            timestamp: now_rfc3339(),
+            chart: self.chart.clone(),
+            env: self.env.clone(),
+            cluster: self.cluster.clone(),
+            namespace: self.namespace.clone(),
+            release: self.release_name(),
+            user: GitSettings::get_user(),
+            sha: self.git.sha.clone(),
+            branch: self.git.branch.clone(),
+            image_tag: self.image_tag.clone(),
+            rops_version: env!("CARGO_PKG_VERSION").to_string(),
+            success: true,
+            error: None,
+        }
+    }
+
+    /// Deploy notification with the details of the release
+    pub fn notification(&self) -> Notification {
+        Notification::new("deploy")
+            .with_field("chart", &self.chart)
+            .with_field("env", &self.env)
+            .with_field("cluster", &self.cluster)
+            .with_field("namespace", &self.namespace)
+            .with_field("release", self.release_name())
+            .with_field("tag", &self.image_tag)
+            .with_field("user", GitSettings::get_user())
+            .with_field("branch", &self.git.branch)
+            .with_field("sha", &self.git.sha)
+    }
+
+    /// Revisions of the release, most recent first
+    pub fn history(&self, max: usize) -> RopsResult<Vec<Revision>> {
+        let release_name = self.release_name();
+        if self.config.chart_type != ChartType::Helm {
+            return Err(RopsError::Error(format!(
+                "History is only available for helm charts - '{}' is a {:?} chart",
+                self.chart, self.config.chart_type
+            )));
+        }
+        let output = Command::new("helm")
+            .arg("history")
+            .arg(&release_name)
+            .arg("--namespace")
+            .arg(&self.namespace)
+            .arg("--max")
+            .arg(max.to_string())
+            .arg("--output")
+            .arg("json")
+            .output()?;
+        if !output.status.success() {
+            return Err(RopsError::Error(format!(
+                "Failed to get history of Helm release '{release_name}': {}",
+                String::from_utf8_lossy(&output.stderr).trim()
+            )));
+        }
+        let mut history: Vec<Revision> = serde_json::from_slice(&output.stdout)?;
+        history.reverse();
+        Ok(history)
+    }
+
+    /// Label selector of the pods and workloads of the release
+    fn selector(&self) -> String {
+        match self.config.chart_type {
+            ChartType::Helm => format!("app.kubernetes.io/instance={}", self.release_name()),
+            _ => format!("{RELEASE_LABEL}={}", self.release_name()),
+        }
+    }
+
+    /// Render the objects of a kustomize or manifests chart for the release
+    ///
+    /// The values of the value layers, values files and set values are substituted
+    /// for `${path}` placeholders in the manifests.
+    fn manifests(&self) -> RopsResult<Manifests> {
+        let path = self.config.chart.replace("{env}", &self.env);
+        let mut manifests = Manifests::render(self.config.chart_type, &path)?;
+        manifests.substitute(&self.user_values()?)?;
+        manifests.label(&self.release_name());
+        let repositories = self
+            .images
+            .iter()
+            .map(|(path, (_, url))| (path.clone(), url.clone()))
+            .collect();
+        manifests.set_images(&repositories, &self.image_tag);
+        Ok(manifests)
+    }
+
+    /// Stream the logs of the release pods through the logger, one thread per container
+    pub fn logs(&self, container: Option<&str>, follow: bool, tail: Option<u64>) -> RopsResult<()> {
+        let client = KubeClient::from_kubeconfig()?;
+        let pods = client.pods(&self.namespace, &self.selector())?;
+        if pods.is_empty() {
+            return Err(RopsError::Error(format!(
+                "No pods found for release '{}' in namespace '{}'",
+                self.release_name(),
+                self.namespace
+            )));
+        }
+        let mut streams = vec![];
+        for pod in pods.iter() {
+            match container {
+                Some(container) if pod.containers.iter().any(|name| name == container) => {
+                    streams.push((pod.name.as_str(), container))
+                }
+                Some(_) => {}
+                None => streams.extend(
+                    pod.containers
+                        .iter()
+                        .map(|name| (pod.name.as_str(), name.as_str())),
+                ),
+            }
+        }
+        if streams.is_empty() {
+            return Err(RopsError::Error(format!(
+                "No pods of release '{}' have a container '{}'",
+                self.release_name(),
+                container.unwrap_or_default()
+            )));
+        }
+        std::thread::scope(|scope| {
+            let handles: Vec<_> = streams
+                .iter()
+                .map(|(pod, container)| {
+                    let client = &client;
+                    scope.spawn(move || -> RopsResult<()> {
+                        let response =
+                            client.logs(&self.namespace, pod, container, follow, tail)?;
+                        for line in BufReader::new(response).lines().map_while(Result::ok) {
+                            log::info!("[{pod}/{container}] {line}");
+                        }
+                        Ok(())
+                    })
+                })
+                .collect();
+            for handle in handles {
+                match handle.join() {
+                    Ok(Err(err)) => log::warn!("{err}"),
+                    Err(err) => log::warn!("Failed to join log stream: {err:?}"),
+                    Ok(Ok(())) => {}
+                }
+            }
+        });
+        Ok(())
+    }
+
+    /// The first ready pod of the release
+    fn ready_pod(&self, client: &KubeClient) -> RopsResult<Pod> {
+        client
+            .pods(&self.namespace, &self.selector())?
+            .into_iter()
+            .find(|pod| pod.ready)
+            .ok_or_else(|| {
+                RopsError::Error(format!(
+                    "No ready pods found for release '{}' in namespace '{}'",
+                    self.release_name(),
+                    self.namespace
+                ))
+            })
+    }
+
+    /// Forward local ports to the release service, or to its first ready pod without a service
+    pub fn port_forward(&self, ports: &[String]) -> RopsResult<()> {
+        for port in ports {
+            let valid = match port.split_once(':') {
+                Some((local, remote)) => {
+                    (local.is_empty() || local.parse::<u16>().is_ok())
+                        && remote.parse::<u16>().is_ok()
+                }
+                None => port.parse::<u16>().is_ok(),
+            };
+            if !valid {
+                return Err(RopsError::Error(format!(
+                    "Invalid port '{port}' - expected local:remote"
+                )));
+            }
+        }
+        let client = KubeClient::from_kubeconfig()?;
+        let target = match client
+            .services(&self.namespace, &self.selector())?
+            .into_iter()
+            .next()
+        {
+            Some(service) => format!("service/{service}"),
+            None => format!("pod/{}", self.ready_pod(&client)?.name),
+        };
+        let mut command = Command::new("kubectl");
+        command
+            .arg("port-forward")
+            .arg(&target)
+            .args(ports)
             .arg("--namespace")
             .arg(&self.namespace);
+        Self::run_interactive(command)
+    }
 
-        if let Some(var_location) = &self.vars {
-            command
-                .arg("-f")
-                .arg(format!("{}/values.yaml", var_location))
-                .arg("-f")
-                .arg(format!("{}/secrets.yaml", var_location));
+    /// Run a command in the first ready pod of the release with the terminal attached
+    pub fn exec(&self, container: Option<&str>, args: &[String]) -> RopsResult<()> {
+        let client = KubeClient::from_kubeconfig()?;
+        let pod = self.ready_pod(&client)?;
+        if let Some(container) = container
+            && !pod.containers.iter().any(|name| name == container)
+        {
+            return Err(RopsError::Error(format!(
+                "Pod '{}' has no container '{container}' - available are {}",
+                pod.name,
+                pod.containers.join(", ")
+            )));
+        }
+        let mut command = Command::new("kubectl");
+        command
+            .arg("exec")
+            .arg(&pod.name)
+            .arg("--namespace")
+            .arg(&self.namespace)
+            .arg("--stdin");
+        if std::io::stdin().is_terminal() {
+            command.arg("--tty");
+        }
+        if let Some(container) = container {
+            command.arg("--container").arg(container);
+        }
+        command.arg("--").args(args);
+        Self::run_interactive(command)
+    }
+
+    /// Run a command with the terminal attached
+    fn run_interactive(command: Command) -> RopsResult<()> {
+        let mut command = StreamCommand::new(command);
+        log::info!("{}", command.format_command());
+        let status = command.command.status()?;
+        if status.success() {
+            Ok(())
+        } else {
+            Err(RopsError::Error(format!(
+                "{} exited with {status}",
+                command.command.get_program().to_string_lossy()
+            )))
+        }
+    }
+
+    /// Events of the workloads, replica sets and pods of the release, oldest first
+    pub fn events(&self) -> RopsResult<Vec<Event>> {
+        let client = KubeClient::from_kubeconfig()?;
+        let selector = self.selector();
+        let workloads: Vec<String> = client
+            .workloads(&self.namespace, &selector)?
+            .into_iter()
+            .map(|workload| workload.name)
+            .collect();
+        let pods: Vec<String> = client
+            .pods(&self.namespace, &selector)?
+            .into_iter()
+            .map(|pod| pod.name)
+            .collect();
+        Ok(client
+            .events(&self.namespace, None)?
+            .into_iter()
+            .filter(|event| {
+                pods.contains(&event.name)
+                    || workloads.iter().any(|workload| {
+                        // replica sets and pods are named after their workload
+                        event.name == *workload || event.name.starts_with(&format!("{workload}-"))
+                    })
+            })
+            .collect())
+    }
 
-            let var_repo = format!("{}/{}", var_location, self.chart);
-            if Path::new(&var_repo).is_dir() {
-                command
-                    .arg("-f")
-                    .arg(format!("{}/values.yaml", var_repo))
-                    .arg("-f")
-                    .arg(format!("{}/secrets.yaml", var_repo));
+    /// Compare the deployed release with what would be deployed now
+    ///
+    /// Checks the release status, chart version, image tags and values
+    pub fn drift(&self) -> RopsResult<Drift> {
+        let release = self.release_name();
+        let desired_version = self.desired_version();
+        let mut drift = Drift {
+            chart: self.chart.clone(),
+            env: self.env.clone(),
+            namespace: self.namespace.clone(),
+            release: release.clone(),
+            status: DriftStatus::InSync,
+            revision: None,
+            deployed_version: None,
+            desired_version: desired_version.clone(),
+            changes: vec![],
+        };
+        if self.config.chart_type != ChartType::Helm {
+            drift.changes = self
+                .manifests()?
+                .diff(&KubeClient::from_kubeconfig()?, &self.namespace)?;
+            if !drift.changes.is_empty() {
+                drift.status = DriftStatus::Modified;
             }
+            return Ok(drift);
         }
-        for set in self.set.iter() {
-            command.arg("--set").arg(set);
+        let output = Command::new("helm")
+            .arg("status")
+            .arg(&release)
+            .arg("--namespace")
+            .arg(&self.namespace)
+            .arg("--output")
+            .arg("json")
+            .output()?;
+        if !output.status.success() {
+            let stderr = String::from_utf8_lossy(&output.stderr);
+            if stderr.contains("not found") {
+                drift.status = DriftStatus::NotDeployed;
+                return Ok(drift);
+            }
+            return Err(RopsError::Error(format!(
+                "Failed to get status of Helm release '{release}': {}",
+                stderr.trim()
+            )));
         }
-        for arg in self.args.iter() {
-            command.arg(arg);
+        let status: ReleaseStatus = serde_json::from_slice(&output.stdout)?;
+        drift.revision = Some(status.version);
+        drift.deployed_version =
+            Some(status.chart.metadata.version.clone()).filter(|version| !version.is_empty());
+        let mut outdated = vec![];
+        if let (Some(deployed), Some(desired)) = (&drift.deployed_version, &desired_version) {
+            let matches = match (semver::Version::parse(deployed), VersionReq::parse(desired)) {
+                (Ok(deployed_version), Ok(constraint))
+                    if semver::Version::parse(desired.trim_start_matches('=')).is_err() =>
+                {
+                    constraint.matches(&deployed_version)
+                }
+                _ => deployed == desired.trim_start_matches('='),
+            };
+            if !matches {
+                outdated.push(format!("version {deployed} -> {desired}"));
+            }
         }
-        if self.wait {
-            command.arg("--wait");
+
+        let output = Command::new("helm")
+            .arg("get")
+            .arg("values")
+            .arg(&release)
+            .arg("--namespace")
+            .arg(&self.namespace)
+            .arg("--output")
+            .arg("json")
+            .output()?;
+        if !output.status.success() {
+            return Err(RopsError::Error(format!(
+                "Failed to get values of Helm release '{release}': {}",
+                String::from_utf8_lossy(&output.stderr).trim()
+            )));
         }
-        self.fetch_cluster()?;
+        let deployed = match serde_json::from_slice(&output.stdout)? {
+            // releases without user supplied values
+            serde_json::Value::Null => serde_json::Value::Object(Default::default()),
+            deployed => deployed,
+        };
+        let desired = serde_json::to_value(self.user_values()?)?;
+        let image_paths: Vec<String> = self.images.keys().cloned().collect();
+        for (path, (image, _)) in self.images.iter() {
+            let (path, expected) = image.reference_value(path, &self.image_tag);
+            let reference = value_at(&deployed, &path).map(|reference| match reference {
+                serde_json::Value::String(reference) => reference.clone(),
+                reference => reference.to_string(),
+            });
+            if reference.as_deref() != Some(expected.as_str()) {
+                outdated.push(format!(
+                    "image {path} {} -> {expected}",
+                    reference.as_deref().unwrap_or("-"),
+                ));
+            }
+        }
+        let mut modified = vec![];
+        diff_values("", &deployed, &desired, &image_paths, &mut modified);
+
+        drift.status = if status.info.status != "deployed" {
+            drift
+                .changes
+                .push(format!("release status {}", status.info.status));
+            DriftStatus::Failed
+        } else if !outdated.is_empty() {
+            DriftStatus::Outdated
+        } else if !modified.is_empty() {
+            DriftStatus::Modified
+        } else {
+            DriftStatus::InSync
+        };
+        drift.changes.extend(outdated);
+        drift.changes.extend(modified);
+        Ok(drift)
+    }
+
+    /// The chart version which would be deployed - the version of local charts is read from Chart.yaml
+    fn desired_version(&self) -> Option<String> {
+        if let Some(version) = self.version() {
+            return Some(version.to_string());
+        }
+        if !self.config.is_local() {
+            return None;
+        }
+        let content = fs::read_to_string(Path::new(&self.config.chart).join("Chart.yaml")).ok()?;
+        let metadata: serde_yaml::Value = serde_yaml::from_str(&content).ok()?;
+        metadata
+            .get("version")
+            .and_then(|version| version.as_str())
+            .map(str::to_string)
+    }
+
+    /// The deploy lock of the release
+    pub fn deploy_lock(&self) -> DeployLock {
+        DeployLock::new(&self.release_name(), &self.namespace)
+    }
+
+    /// Lock information for the current user and commit
+    pub fn lock_info(&self) -> LockInfo {
+        LockInfo {
+            owner: GitSettings::get_user(),
+            chart: self.chart.clone(),
+            env: self.env.clone(),
+            release: self.release_name(),
+            sha: self.git.sha.clone(),
+            branch: self.git.branch.clone(),
+            timestamp: now_rfc3339(),
+            message: None,
+            token: Some(LockInfo::process_token()),
+        }
+    }
+
+    /// Verify the health of the release, rolling back to the previous revision on failure
+    fn check_health(&self, health: &HealthCheck) -> RopsResult<()> {
+        let release_name = self.release_name();
+        log::info!("Verifying health of release '{release_name}'");
+        let failures =
+            health.verify(&release_name, &self.selector(), &self.namespace, &self.env)?;
+        if failures.is_empty() {
+            log::info!("Release '{release_name}' is healthy");
+            return Ok(());
+        }
+        for failure in failures.iter() {
+            log::error!("Health check failed: {failure}");
+        }
+        let rollback = if health.rollback {
+            match self.rollback() {
+                Ok(()) => "rolled back to the previous revision".to_string(),
+                Err(err) => format!("rollback failed - {err}"),
+            }
+        } else {
+            "rollback disabled".to_string()
+        };
+        Err(RopsError::Error(format!(
+            "Release '{release_name}' is unhealthy ({rollback}): {}",
+            failures.join("; ")
+        )))
+    }
+
+    /// Rollback the release to the previous revision
+    pub fn rollback(&self) -> RopsResult<()> {
+        let release_name = self.release_name();
+        if self.config.chart_type != ChartType::Helm {
+            return Err(RopsError::Error(format!(
+                "Rollback is only supported for helm charts - redeploy a previous commit of '{}'",
+                self.chart
+            )));
+        }
+        let mut command = Command::new("helm");
+        command
+            .arg("rollback")
+            .arg(&release_name)
+            .arg("--namespace")
+            .arg(&self.namespace)
+            .arg("--wait");
         if StreamCommand::new(command)
             .with_dry_run(self.dry_run)
             .run()?
@@ -285,42 +2503,611 @@ impl DeployChart {
             Ok(())
         } else {
             Err(RopsError::Error(format!(
-                "Failed to deploy Helm repo '{}'",
-                chart_name
+                "Failed to rollback Helm release '{}'",
+                release_name
             )))
         }
     }
 
-    pub fn fetch_cluster(&self) -> RopsResult<()> {
-        let mut command = Command::new("aws");
-        command
-            .arg("eks")
-            .arg("update-kubeconfig")
-            .arg("--name")
-            .arg(&self.cluster);
+    /// Render the chart manifests locally with the same values used by deploy
+    ///
+    /// Manifests are written to stdout unless an output directory is given
+    pub fn template(&self, output_dir: Option<&str>) -> RopsResult<()> {
+        self.prepare()?;
+        if self.config.chart_type != ChartType::Helm {
+            let yaml = self.manifests()?.to_yaml()?;
+            match output_dir {
+                Some(output_dir) => {
+                    fs::create_dir_all(output_dir)?;
+                    let path = Path::new(output_dir).join(format!("{}.yaml", self.release_name()));
+                    fs::write(&path, yaml)?;
+                    log::info!("Manifests written to {}", path.display());
+                }
+                None => print!("{yaml}"),
+            }
+            return Ok(());
+        }
+        let (mut command, _decrypted) = self.helm_command("template")?;
+        if let Some(output_dir) = output_dir {
+            command.arg("--output-dir").arg(output_dir);
+        }
         if StreamCommand::new(command)
             .with_dry_run(self.dry_run)
+            .with_inherit_stdout(output_dir.is_none())
             .run()?
         {
             Ok(())
         } else {
             Err(RopsError::Error(format!(
-                "Failed to update kubeconfig for cluster '{}'",
-                self.cluster
+                "Failed to render Helm chart '{}'",
+                self.chart
             )))
         }
     }
 
-    pub fn add_helm_repo(&self, repo_name: &str, repo_url: &str) -> RopsResult<()> {
+    /// The chart version to deploy - the locked version if available, otherwise
+    /// the version constraint of the chart
+    pub fn version(&self) -> Option<&str> {
+        match (self.locked.as_ref(), self.config.version.as_deref()) {
+            (Some(locked), _) => Some(locked.version.as_str()),
+            (None, Some(version)) => {
+                if semver::Version::parse(version.trim_start_matches('=')).is_err() {
+                    log::warn!(
+                        "Chart '{}' version '{version}' is not locked for '{}' - run `rops charts resolve` for reproducible deploys",
+                        self.chart,
+                        self.env
+                    );
+                }
+                Some(version)
+            }
+            (None, None) => None,
+        }
+    }
+
+    /// Resolve the chart version constraint to the exact version helm would deploy
+    pub fn resolve_version(&self) -> RopsResult<LockedChart> {
+        self.prepare_repos()?;
+        let mut command = Command::new("helm");
+        command.arg("show").arg("chart").arg(&self.config.chart);
+        if let Some(version) = self.config.version.as_ref() {
+            command.arg("--version").arg(version);
+        }
+        let output = command.output()?;
+        if !output.status.success() {
+            return Err(RopsError::Error(format!(
+                "Failed to resolve chart '{}': {}",
+                self.config.chart,
+                String::from_utf8_lossy(&output.stderr).trim()
+            )));
+        }
+        let metadata: serde_yaml::Value = serde_yaml::from_slice(&output.stdout)?;
+        let version = metadata
+            .get("version")
+            .and_then(|version| version.as_str())
+            .ok_or_else(|| {
+                RopsError::Error(format!(
+                    "Chart '{}' metadata has no version",
+                    self.config.chart
+                ))
+            })?;
+        Ok(LockedChart {
+            chart: self.config.chart.clone(),
+            constraint: self.config.version.clone(),
+            version: version.to_string(),
+        })
+    }
+
+    /// Values files of the value layers which exist, followed by the local values files
+    fn value_files(&self) -> RopsResult<Vec<ValueFile>> {
+        let mut value_files = vec![];
+        for layer in self.layers.iter() {
+            let directory = match (layer.path.contains("{vars}"), self.vars.as_ref()) {
+                (true, None) => continue,
+                (_, vars) => layer
+                    .path
+                    .replace("{vars}", vars.map(String::as_str).unwrap_or_default())
+                    .replace("{env}", &self.env)
+                    .replace("{chart}", &self.chart),
+            };
+            for (file_name, secret) in [("values.yaml", false), ("secrets.yaml", true)] {
+                let path = Path::new(&directory).join(file_name);
+                if path.is_file() {
+                    log::info!("Values layer '{}': {}", layer.name, path.display());
+                    value_files.push(ValueFile { path, secret });
+                } else {
+                    log::debug!(
+                        "Values layer '{}': {} not found",
+                        layer.name,
+                        path.display()
+                    );
+                }
+            }
+        }
+        for values in self.values.iter() {
+            let path = Path::new(values).to_path_buf();
+            if !path.is_file() {
+                return Err(RopsError::Error(format!(
+                    "Values file '{values}' not found"
+                )));
+            }
+            log::info!("Values layer 'local': {}", path.display());
+            let secret = Sops::is_encrypted(&path)?;
+            value_files.push(ValueFile { path, secret });
+        }
+        Ok(value_files)
+    }
+
+    /// Set values passed to helm - images, chart set values and command line set values
+    fn set_values(&self) -> Vec<String> {
+        self.images
+            .iter()
+            .flat_map(|(path, (image, url))| image.set_values(path, url, &self.image_tag))
+            .chain(self.config.set.iter().cloned())
+            .chain(self.set.iter().cloned())
+            .collect()
+    }
+
+    /// The effective values of the release - the chart default values merged with the
+    /// user supplied values
+    pub fn merged_values(&self) -> RopsResult<serde_yaml::Value> {
+        if self.config.chart_type != ChartType::Helm {
+            return self.user_values();
+        }
+        let mut merged = self.chart_values()?;
+        merge_yaml(&mut merged, self.user_values()?);
+        Ok(merged)
+    }
+
+    /// The default values of the chart reported by `helm show values`
+    fn chart_values(&self) -> RopsResult<serde_yaml::Value> {
+        let mut command = Command::new("helm");
+        command.arg("show").arg("values").arg(&self.config.chart);
+        if let Some(version) = self.version() {
+            command.arg("--version").arg(version);
+        }
+        let output = command.output()?;
+        if !output.status.success() {
+            return Err(RopsError::Error(format!(
+                "Failed to get the default values of chart '{}': {}",
+                self.config.chart,
+                String::from_utf8_lossy(&output.stderr).trim()
+            )));
+        }
+        match serde_yaml::from_slice(&output.stdout)? {
+            // charts without default values
+            serde_yaml::Value::Null => Ok(serde_yaml::Value::Mapping(Default::default())),
+            values => Ok(values),
+        }
+    }
+
+    /// The user supplied values of the release - value files merged in order with set
+    /// values applied, as reported by `helm get values`
+    pub fn user_values(&self) -> RopsResult<serde_yaml::Value> {
+        let sops = Sops::default();
+        let mut merged = serde_yaml::Value::Mapping(Default::default());
+        for value_file in self.value_files()? {
+            let content = if value_file.secret {
+                sops.decrypt(&value_file.path)?
+            } else {
+                fs::read(&value_file.path)?
+            };
+            let mut values: serde_yaml::Value = serde_yaml::from_slice(&content)?;
+            if let serde_yaml::Value::Mapping(mapping) = &mut values {
+                mapping.remove("sops");
+            }
+            merge_yaml(&mut merged, values);
+        }
+        for set in self.set_values() {
+            for pair in split_set_values(&set) {
+                let (path, value) = pair.split_once('=').ok_or_else(|| {
+                    RopsError::Error(format!("Invalid set value '{set}' - expected path=value"))
+                })?;
+                set_yaml_path(&mut merged, path, value);
+            }
+        }
+        Ok(merged)
+    }
+
+    /// Check that the images of the chart exist in the registry with the deploy tag
+    fn verify_images(&self) -> RopsResult<()> {
+        for (_, image_url) in self.images.values() {
+            let image = image_reference(image_url, &self.image_tag);
+            log::info!("Verifying image {image} exists");
+            let output = Command::new("docker")
+                .arg("manifest")
+                .arg("inspect")
+                .arg(&image)
+                .output()?;
+            if !output.status.success() {
+                return Err(RopsError::DockerError(format!(
+                    "Image '{image}' not found in registry: {}",
+                    String::from_utf8_lossy(&output.stderr).trim()
+                )));
+            }
+        }
+        Ok(())
+    }
+
+    /// Checkout git repos and add helm repos required by the chart
+    fn prepare(&self) -> RopsResult<()> {
+        for (repo_name, repo) in self.config.git_repos.iter() {
+            self.git.checkout_repo(repo_name, repo)?;
+        }
+        if self.config.chart_type != ChartType::Helm {
+            return Ok(());
+        }
+        self.prepare_repos()
+    }
+
+    /// Add helm repos and login to OCI registries required by the chart
+    ///
+    /// Only repos which are missing, have a different url or a stale index are touched
+    fn prepare_repos(&self) -> RopsResult<()> {
+        for repo in self.config.helm_repos.values() {
+            if let Some(host) = oci_host(repo) {
+                self.registry_login(host)?;
+            }
+        }
+        let repos = HelmRepos::chart_repos(std::iter::once(&self.config))?;
+        if !repos.is_empty() {
+            HelmRepos::load()?.sync(&repos, Some(self.helm_repo_ttl), false)?;
+        }
+        if let Some(host) = oci_host(&self.config.chart) {
+            self.registry_login(host)?;
+        }
+        Ok(())
+    }
+
+    /// Build a helm command for the release with values files, set values and extra arguments
+    ///
+    /// Secrets files are decrypted with sops into temporary files which are removed
+    /// once the returned files are dropped
+    fn helm_command(&self, action: &str) -> RopsResult<(Command, Vec<NamedTempFile>)> {
+        let mut command = Command::new("helm");
+        command
+            .arg(action)
+            .arg(self.release_name())
+            .arg(&self.config.chart)
+            .arg("--namespace")
+            .arg(&self.namespace);
+        if let Some(version) = self.version() {
+            command.arg("--version").arg(version);
+        }
+
+        let mut decrypted = vec![];
+        let sops = Sops::default();
+        for value_file in self.value_files()? {
+            if !value_file.secret || self.dry_run {
+                command.arg("-f").arg(&value_file.path);
+            } else {
+                let file = sops.decrypt_to_temp(&value_file.path)?;
+                log::info!(
+                    "Decrypted {} into {}",
+                    value_file.path.display(),
+                    file.path().display()
+                );
+                command.arg("-f").arg(file.path());
+                decrypted.push(file);
+            }
+        }
+        for set in self.set_values() {
+            command.arg("--set").arg(set);
+        }
+        for arg in self.config.args.iter().chain(self.args.iter()) {
+            command.arg(arg);
+        }
+        Ok((command, decrypted))
+    }
+
+    pub fn uninstall(&self) -> RopsResult<()> {
+        let release_name = self.release_name();
+        if self.config.chart_type != ChartType::Helm {
+            self.fetch_cluster()?;
+            return Manifests::prune(
+                &self.kube_client()?,
+                &release_name,
+                &self.namespace,
+                None,
+                self.dry_run,
+            );
+        }
         let mut command = Command::new("helm");
-        command.arg("repo").arg("add").arg(repo_name).arg(repo_url);
-        if StreamCommand::new(command).run()? {
+        command
+            .arg("uninstall")
+            .arg(&release_name)
+            .arg("--namespace")
+            .arg(&self.namespace);
+        if self.wait {
+            command.arg("--wait");
+        }
+        self.fetch_cluster()?;
+        if StreamCommand::new(command)
+            .with_dry_run(self.dry_run)
+            .run()?
+        {
+            Ok(())
+        } else {
+            Err(RopsError::Error(format!(
+                "Failed to uninstall Helm release '{}'",
+                release_name
+            )))
+        }
+    }
+
+    pub fn fetch_cluster(&self) -> RopsResult<()> {
+        ChartsSettings::fetch_cluster(&self.cluster, self.dry_run)
+    }
+
+    /// API client of the chart cluster for applying and pruning manifests
+    ///
+    /// Dry runs skip `fetch_cluster`, so the kubeconfig of the cluster is fetched here -
+    /// dry runs only read from the API, and must not use whatever context is current.
+    fn kube_client(&self) -> RopsResult<KubeClient> {
+        if self.dry_run {
+            ChartsSettings::fetch_cluster(&self.cluster, false)?;
+        }
+        KubeClient::from_kubeconfig()
+    }
+
+    /// Login helm to an OCI registry reusing the docker credentials
+    pub fn registry_login(&self, host: &str) -> RopsResult<()> {
+        let Some((username, password)) = DockerSettings::registry_credentials(host)? else {
+            log::info!(
+                "No docker credentials for '{host}' - using the existing helm registry login"
+            );
+            return Ok(());
+        };
+        log::info!("helm registry login {host} --username {username} --password-stdin");
+        if self.dry_run {
+            log::info!("Dry run mode enabled, skipping actual command execution.");
+            return Ok(());
+        }
+        let mut child = Command::new("helm")
+            .arg("registry")
+            .arg("login")
+            .arg(host)
+            .arg("--username")
+            .arg(&username)
+            .arg("--password-stdin")
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .spawn()?;
+        if let Some(mut stdin) = child.stdin.take() {
+            stdin.write_all(password.value().as_bytes())?;
+        }
+        let output = child.wait_with_output()?;
+        if output.status.success() {
             Ok(())
         } else {
             Err(RopsError::Error(format!(
-                "Failed to add Helm repo '{}'",
-                repo_name
+                "Failed to login to registry '{host}': {}",
+                String::from_utf8_lossy(&output.stderr).trim()
             )))
         }
     }
 }
+
+/// Line and column of the deepest key of a path found in a yaml document
+///
+/// Keys are matched by indentation, a sequence index such as `routes[0]` stops the
+/// search at the sequence key.
+fn key_location(content: &str, keys: &[&str]) -> Option<(usize, usize)> {
+    let mut location = None;
+    // indentation of the parent mapping, none for the root
+    let mut parent: Option<usize> = None;
+    let mut lines = content.lines().enumerate();
+    'keys: for key in keys {
+        let (key, indexed) = match key.split_once('[') {
+            Some((key, _)) => (key, true),
+            None => (*key, false),
+        };
+        let mut level = None;
+        for (number, line) in lines.by_ref() {
+            let trimmed = line.trim_start();
+            if trimmed.is_empty() || trimmed.starts_with('#') {
+                continue;
+            }
+            let indent = line.len() - trimmed.len();
+            if parent.is_some_and(|parent| indent <= parent) {
+                break 'keys;
+            }
+            if *level.get_or_insert(indent) != indent {
+                continue;
+            }
+            let found = [
+                format!("{key}:"),
+                format!("\"{key}\":"),
+                format!("'{key}':"),
+            ]
+            .iter()
+            .any(|prefix| trimmed.starts_with(prefix.as_str()));
+            if found {
+                location = Some((number + 1, indent + 1));
+                parent = Some(indent);
+                if indexed {
+                    break 'keys;
+                }
+                continue 'keys;
+            }
+        }
+        break;
+    }
+    location
+}
+
+/// The registry host of an OCI reference
+fn oci_host(reference: &str) -> Option<&str> {
+    reference
+        .strip_prefix("oci://")
+        .and_then(|reference| reference.split('/').next())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn authorize_enforces_policy() {
+        let settings = ChartsSettings {
+            envs: HashMap::from([(
+                "prod".to_string(),
+                EnvConfig {
+                    cluster: "prod-cluster".to_string(),
+                    protected: true,
+                    allowed_branches: vec!["main".to_string(), "release/*".to_string()],
+                    ..Default::default()
+                },
+            )]),
+            ..Default::default()
+        };
+        let git = |branch: &str| GitSettings {
+            branch: branch.to_string(),
+            ..Default::default()
+        };
+        let err = settings
+            .authorize("prod", "preview 'api'", &git("feature/x"), false, true)
+            .unwrap_err();
+        assert!(err.to_string().contains("allowed_branches"), "{err}");
+        // dry runs only warn about violations
+        settings
+            .authorize("prod", "uninstall 'api'", &git("feature/x"), true, false)
+            .unwrap();
+        settings
+            .authorize("prod", "deploy 'api'", &git("release/1.2"), false, true)
+            .unwrap();
+        settings
+            .authorize("staging", "deploy 'api'", &git("feature/x"), false, false)
+            .unwrap();
+    }
+
+    #[test]
+    fn silent_failing_hook_aborts_deploy() {
+        let dir = tempfile::tempdir().unwrap();
+        let marker = |name: &str| dir.path().join(name).display().to_string();
+        let deploy_chart = DeployChart {
+            chart: "api".to_string(),
+            config: Chart {
+                hooks: ChartHooks {
+                    pre_deploy: vec!["exit 1".to_string()],
+                    post_deploy: vec![format!("touch {}", marker("deployed"))],
+                    on_failure: vec![format!("touch {}", marker("failed"))],
+                },
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+        let err = deploy_chart.upgrade_with_hooks().unwrap_err();
+        assert!(err.to_string().contains("pre-deploy hook"), "{err}");
+        assert!(!Path::new(&marker("deployed")).exists());
+        assert!(Path::new(&marker("failed")).exists());
+    }
+
+    #[test]
+    fn image_set_values() {
+        let images: BTreeMap<String, ChartImage> = serde_yaml::from_str(
+            "image.tag: api\nworker.image:\n  name: worker\n  repository: repo\n",
+        )
+        .unwrap();
+        let deploy_chart = |image_tag: &str| DeployChart {
+            images: images
+                .iter()
+                .map(|(path, image)| {
+                    let url = format!("reg.io/{}", image.name());
+                    (path.clone(), (image.clone(), url))
+                })
+                .collect(),
+            image_tag: image_tag.to_string(),
+            ..Default::default()
+        };
+        assert_eq!(
+            deploy_chart("v1.2").set_values(),
+            vec![
+                "image.tag=v1.2",
+                "worker.image.repo=reg.io/worker",
+                "worker.image.tag=v1.2"
+            ]
+        );
+        for reference in ["sha256:4f1c", "@sha256:4f1c"] {
+            assert_eq!(
+                deploy_chart(reference).set_values()[1..],
+                [
+                    "worker.image.repo=reg.io/worker",
+                    "worker.image.digest=sha256:4f1c"
+                ]
+            );
+        }
+        assert_eq!(
+            image_reference("reg.io/api", "@sha256:4f1c"),
+            "reg.io/api@sha256:4f1c"
+        );
+        assert_eq!(image_reference("reg.io/api", "v1.2"), "reg.io/api:v1.2");
+    }
+
+    #[test]
+    fn local_charts() {
+        let chart = |chart: &str| Chart {
+            chart: chart.to_string(),
+            helm_repos: HashMap::from([(
+                "src".to_string(),
+                "https://charts.example.com".to_string(),
+            )]),
+            ..Default::default()
+        };
+        assert!(chart("./charts/web").is_local());
+        assert!(chart("/srv/charts/web").is_local());
+        assert!(!chart("oci://reg.io/charts/web").is_local());
+        assert!(!chart("https://example.com/web-1.0.0.tgz").is_local());
+        // a repo chart is not local when a directory of the same name exists
+        assert!(Path::new("src/../src").is_dir());
+        assert!(!chart("src/../src").is_local());
+        let unknown_repo = Chart {
+            helm_repos: HashMap::new(),
+            ..chart("src/../src")
+        };
+        assert!(unknown_repo.is_local());
+    }
+
+    #[test]
+    fn oci_hosts() {
+        assert_eq!(oci_host("oci://reg.io/charts/web"), Some("reg.io"));
+        assert_eq!(oci_host("oci://localhost:5000/web"), Some("localhost:5000"));
+        assert_eq!(
+            oci_host("oci://123.dkr.ecr.eu-west-1.amazonaws.com"),
+            Some("123.dkr.ecr.eu-west-1.amazonaws.com")
+        );
+        assert_eq!(oci_host("bitnami/redis"), None);
+        assert_eq!(oci_host("https://charts.example.com"), None);
+    }
+
+    #[test]
+    fn key_locations() {
+        let content = "\
+# charts
+api:
+  chart: ./api
+web:
+  chart: ./web
+  envs:
+    prod:
+      namespace: web
+  block:
+    routes:
+      - name: web
+";
+        assert_eq!(key_location(content, &["web"]), Some((4, 1)));
+        assert_eq!(key_location(content, &["web", "chart"]), Some((5, 3)));
+        assert_eq!(
+            key_location(content, &["web", "envs", "prod", "namespace"]),
+            Some((8, 7))
+        );
+        assert_eq!(
+            key_location(content, &["web", "block", "routes[0]", "name"]),
+            Some((10, 5))
+        );
+        // missing keys are located at their deepest existing parent
+        assert_eq!(key_location(content, &["api", "block"]), Some((2, 1)));
+        assert_eq!(key_location(content, &["web", "version"]), Some((4, 1)));
+        assert_eq!(key_location(content, &["other"]), None);
+    }
+}
@@ -1,11 +1,16 @@
 use crate::settings::Settings;
 use crate::{
     error::{RopsError, RopsResult},
-    utils::{StreamCommand, get_default_from_env},
+    notifications::Notification,
+    utils::{Secret, StreamCommand, get_default_from_env},
 };
+use base64::{Engine as _, engine::general_purpose};
 use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::io::Write;
 use std::path::Path;
-use std::process::Command;
+use std::process::{Command, Stdio};
+use std::time::Instant;
 
 #[derive(Debug, Default, Clone, Deserialize, Serialize)]
 pub struct DockerSettings {
@@ -21,6 +26,30 @@ pub struct DockerSettings {
     pub git_sha_arg: Option<String>,
 }
 
+/// The subset of the docker `config.json` file used to look up registry credentials
+#[derive(Debug, Default, Deserialize)]
+struct DockerConfig {
+    #[serde(default)]
+    auths: HashMap<String, DockerAuth>,
+    #[serde(default, rename = "credsStore")]
+    creds_store: Option<String>,
+    #[serde(default, rename = "credHelpers")]
+    cred_helpers: HashMap<String, String>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+struct DockerAuth {
+    auth: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+struct HelperCredentials {
+    #[serde(rename = "Username")]
+    username: String,
+    #[serde(rename = "Secret")]
+    secret: String,
+}
+
 fn _default_docker_files_path() -> String {
     get_default_from_env("DOCKER_FILES_PATH", "".into())
 }
@@ -36,6 +65,97 @@ fn _default_docker_image_repo_url() -> String {
 fn _default_docker_git_sha_arg() -> Option<String> {
     get_default_from_env("DOCKER_GIT_SHA_ARG", None)
 }
+
+impl DockerSettings {
+    /// Get the credentials docker uses for a registry host
+    ///
+    /// Credentials are looked up in the docker config file, using the credential
+    /// helpers when configured
+    pub fn registry_credentials(host: &str) -> RopsResult<Option<(String, Secret)>> {
+        let config_dir = match std::env::var("DOCKER_CONFIG") {
+            Ok(dir) => dir.into(),
+            Err(_) => match std::env::home_dir() {
+                Some(home) => home.join(".docker"),
+                None => return Ok(None),
+            },
+        };
+        let config_path = config_dir.join("config.json");
+        if !config_path.exists() {
+            return Ok(None);
+        }
+        let config: DockerConfig = serde_json::from_str(&std::fs::read_to_string(&config_path)?)?;
+        if let Some(helper) = config
+            .cred_helpers
+            .get(host)
+            .or(config.creds_store.as_ref())
+        {
+            return Self::helper_credentials(helper, host);
+        }
+        let auth = config.auths.iter().find_map(|(key, auth)| {
+            let key = key
+                .trim_start_matches("https://")
+                .trim_start_matches("http://");
+            let key = key.split('/').next().unwrap_or(key);
+            if key == host {
+                auth.auth.as_ref()
+            } else {
+                None
+            }
+        });
+        match auth {
+            Some(auth) => {
+                let decoded = general_purpose::STANDARD.decode(auth).map_err(|err| {
+                    RopsError::DockerError(format!("Invalid docker auth for '{host}': {err}"))
+                })?;
+                let decoded = String::from_utf8_lossy(&decoded).to_string();
+                match decoded.split_once(':') {
+                    Some((username, password)) => Ok(Some((
+                        username.to_string(),
+                        Secret::new(password.to_string()),
+                    ))),
+                    None => Err(RopsError::DockerError(format!(
+                        "Invalid docker auth for '{host}'"
+                    ))),
+                }
+            }
+            None => Ok(None),
+        }
+    }
+
+    fn helper_credentials(helper: &str, host: &str) -> RopsResult<Option<(String, Secret)>> {
+        let child = Command::new(format!("docker-credential-{helper}"))
+            .arg("get")
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .spawn();
+        // a helper configured for the desktop app may not be installed, e.g. in CI
+        let mut child = match child {
+            Ok(child) => child,
+            Err(err) => {
+                log::warn!("Failed to run docker-credential-{helper} for '{host}': {err}");
+                return Ok(None);
+            }
+        };
+        if let Some(mut stdin) = child.stdin.take() {
+            stdin.write_all(host.as_bytes())?;
+        }
+        let output = child.wait_with_output()?;
+        if !output.status.success() {
+            log::debug!(
+                "docker-credential-{helper} has no credentials for '{host}': {}",
+                String::from_utf8_lossy(&output.stdout).trim()
+            );
+            return Ok(None);
+        }
+        let credentials: HelperCredentials = serde_json::from_slice(&output.stdout)?;
+        Ok(Some((
+            credentials.username,
+            Secret::new(credentials.secret),
+        )))
+    }
+}
+
 #[derive(clap::Subcommand, Debug, Clone)]
 pub enum DockerCommand {
     /// Build a new Docker image
@@ -114,33 +234,23 @@ impl DockerCommand {
                 }
             }
             Self::Push { name, arch } => {
-                let image_name = settings.get_repo_name(name);
-                let tag = self.get_push_tag(name, *arch, settings);
-
-                let mut command = Command::new("docker");
-                command
-                    .arg("tag")
-                    .arg(&image_name) // Correct image name
-                    .arg(&tag);
-
-                if !StreamCommand::new(command).run()? {
-                    return Err(RopsError::DockerError(format!(
-                        "Docker tag failed for {}",
-                        tag
-                    )));
-                }
-
-                // Push all tags with --all-tags flag
-                let mut command = Command::new("docker");
-                command // Disable Docker BuildKit
-                    .arg("push")
-                    .arg(&tag);
-
-                if StreamCommand::new(command).run()? {
-                    Ok(())
-                } else {
-                    Err(RopsError::DockerError("Docker push failed".to_string()))
-                }
+                let started = Instant::now();
+                let result = self.push(name, *arch, settings);
+                let image = self.get_push_tag(name, *arch, settings);
+                // the pushed tag, with the arch suffix
+                let tag = image
+                    .rsplit_once(':')
+                    .map(|(_, tag)| tag.to_string())
+                    .unwrap_or_default();
+                settings.notifications.notify(
+                    &Notification::new("push")
+                        .with_field("image", image)
+                        .with_field("tag", tag)
+                        .with_field("branch", &settings.git.branch)
+                        .with_field("sha", &settings.git.sha)
+                        .with_result(&result, started.elapsed()),
+                );
+                result
             }
             Self::Manifest { name } => {
                 let manifest_tag = self.get_push_tag(name, false, settings);
@@ -155,6 +265,37 @@ impl DockerCommand {
         }
     }
 
+    /// Tag the image with the push tag and push it to the registry
+    fn push(&self, name: &str, arch: bool, settings: &Settings) -> RopsResult<()> {
+        let image_name = settings.get_repo_name(name);
+        let tag = self.get_push_tag(name, arch, settings);
+
+        let mut command = Command::new("docker");
+        command
+            .arg("tag")
+            .arg(&image_name) // Correct image name
+            .arg(&tag);
+
+        if !StreamCommand::new(command).run()? {
+            return Err(RopsError::DockerError(format!(
+                "Docker tag failed for {}",
+                tag
+            )));
+        }
+
+        // Push all tags with --all-tags flag
+        let mut command = Command::new("docker");
+        command // Disable Docker BuildKit
+            .arg("push")
+            .arg(&tag);
+
+        if StreamCommand::new(command).run()? {
+            Ok(())
+        } else {
+            Err(RopsError::DockerError("Docker push failed".to_string()))
+        }
+    }
+
     fn push_manifest(
         &self,
         manifest_tag: &str,
@@ -259,3 +400,29 @@ impl DockerCommand {
         None
     }
 }
+
+/// The digest of an image reference given as `@sha256:...` or `sha256:...`
+pub fn image_digest(reference: &str) -> Option<&str> {
+    let digest = reference.strip_prefix('@').unwrap_or(reference);
+    digest.starts_with("sha256:").then_some(digest)
+}
+
+/// Image of a repository url with a tag or a digest reference
+pub fn image_reference(url: &str, reference: &str) -> String {
+    match image_digest(reference) {
+        Some(digest) => format!("{url}@{digest}"),
+        None => format!("{url}:{reference}"),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn missing_credential_helper() {
+        let credentials =
+            DockerSettings::helper_credentials("rops-test-missing", "reg.example.com").unwrap();
+        assert!(credentials.is_none());
+    }
+}
@@ -0,0 +1,277 @@
+use crate::error::RopsError;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+/// Drift state of a release compared with what rops would deploy
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum DriftStatus {
+    InSync,
+    /// the release was never deployed to the environment - not counted as drift
+    NotDeployed,
+    /// the drift of the release could not be checked
+    Error,
+    /// the last deploy of the release failed
+    Failed,
+    /// chart version or image tags differ
+    Outdated,
+    /// values were changed outside of rops
+    Modified,
+}
+
+/// Drift of a chart release in an environment
+#[derive(Debug, Clone, Serialize)]
+pub struct Drift {
+    pub chart: String,
+    pub env: String,
+    pub namespace: String,
+    pub release: String,
+    pub status: DriftStatus,
+    pub revision: Option<u32>,
+    pub deployed_version: Option<String>,
+    pub desired_version: Option<String>,
+    /// human readable differences - values are never included
+    pub changes: Vec<String>,
+}
+
+/// A release as reported by `helm status`
+#[derive(Debug, Default, Clone, Deserialize)]
+pub struct ReleaseStatus {
+    #[serde(default)]
+    pub version: u32,
+    #[serde(default)]
+    pub info: ReleaseInfo,
+    #[serde(default)]
+    pub chart: ReleaseChart,
+}
+
+#[derive(Debug, Default, Clone, Deserialize)]
+pub struct ReleaseInfo {
+    #[serde(default)]
+    pub status: String,
+}
+
+#[derive(Debug, Default, Clone, Deserialize)]
+pub struct ReleaseChart {
+    #[serde(default)]
+    pub metadata: ReleaseMetadata,
+}
+
+#[derive(Debug, Default, Clone, Deserialize)]
+pub struct ReleaseMetadata {
+    #[serde(default)]
+    pub version: String,
+}
+
+impl Drift {
+    /// A release whose drift could not be checked
+    pub fn error(chart: &str, env: &str, namespace: &str, err: &RopsError) -> Self {
+        Self {
+            chart: chart.to_string(),
+            env: env.to_string(),
+            namespace: namespace.to_string(),
+            release: String::new(),
+            status: DriftStatus::Error,
+            revision: None,
+            deployed_version: None,
+            desired_version: None,
+            changes: vec![err.to_string()],
+        }
+    }
+
+    /// Check if the release differs from what would be deployed
+    pub fn drifted(&self) -> bool {
+        !matches!(self.status, DriftStatus::InSync | DriftStatus::NotDeployed)
+    }
+
+    /// Print drifts as a table
+    pub fn print_table(drifts: &[Drift]) {
+        println!(
+            "{:<24} {:<16} {:<13} {:<9} {:<24} CHANGES",
+            "CHART", "NAMESPACE", "STATUS", "REVISION", "VERSION"
+        );
+        for drift in drifts {
+            let version = match (&drift.deployed_version, &drift.desired_version) {
+                (Some(deployed), Some(desired)) if deployed != desired => {
+                    format!("{deployed} -> {desired}")
+                }
+                (Some(version), _) | (None, Some(version)) => version.clone(),
+                (None, None) => "-".to_string(),
+            };
+            println!(
+                "{:<24} {:<16} {:<13} {:<9} {:<24} {}",
+                drift.chart,
+                drift.namespace,
+                serde_json::to_value(drift.status)
+                    .ok()
+                    .and_then(|status| status.as_str().map(str::to_string))
+                    .unwrap_or_default(),
+                drift
+                    .revision
+                    .map(|revision| revision.to_string())
+                    .unwrap_or_else(|| "-".to_string()),
+                version,
+                drift.changes.join("; ")
+            );
+        }
+    }
+}
+
+/// Collect the paths of values which differ between the deployed and desired values
+///
+/// Scalars are compared with their type, as helm types values files and `--set` values,
+/// numbers by their value. Paths in `skip` are ignored.
+pub fn diff_values(
+    path: &str,
+    deployed: &Value,
+    desired: &Value,
+    skip: &[String],
+    changes: &mut Vec<String>,
+) {
+    if skip.iter().any(|skip| skip == path) {
+        return;
+    }
+    let child = |key: &str| {
+        if path.is_empty() {
+            key.to_string()
+        } else {
+            format!("{path}.{key}")
+        }
+    };
+    match (deployed, desired) {
+        (Value::Object(deployed), Value::Object(desired)) => {
+            let mut keys: Vec<&String> = deployed.keys().chain(desired.keys()).collect();
+            keys.sort();
+            keys.dedup();
+            for key in keys {
+                diff_values(
+                    &child(key),
+                    deployed.get(key).unwrap_or(&Value::Null),
+                    desired.get(key).unwrap_or(&Value::Null),
+                    skip,
+                    changes,
+                );
+            }
+        }
+        (Value::Array(deployed_items), Value::Array(desired_items))
+            if deployed_items.len() == desired_items.len() =>
+        {
+            for (index, (deployed, desired)) in
+                deployed_items.iter().zip(desired_items.iter()).enumerate()
+            {
+                diff_values(
+                    &format!("{path}[{index}]"),
+                    deployed,
+                    desired,
+                    skip,
+                    changes,
+                );
+            }
+        }
+        (Value::Null, Value::Null) => {}
+        (Value::Null, _) => changes.push(format!("{path} not deployed")),
+        (_, Value::Null) => changes.push(format!("{path} added outside rops")),
+        (deployed, desired) if !same_scalar(deployed, desired) => {
+            changes.push(format!("{path} changed"))
+        }
+        _ => {}
+    }
+}
+
+/// Integers and floats are the same number in json, e.g. `1` and `1.0`
+fn same_scalar(deployed: &Value, desired: &Value) -> bool {
+    match (deployed, desired) {
+        (Value::Number(deployed), Value::Number(desired)) => {
+            deployed == desired || deployed.as_f64() == desired.as_f64()
+        }
+        (deployed, desired) => deployed == desired,
+    }
+}
+
+/// The value at a dot separated path
+pub fn value_at<'a>(value: &'a Value, path: &str) -> Option<&'a Value> {
+    path.split('.')
+        .try_fold(value, |value, key| value.get(key))
+        .filter(|value| !value.is_null())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::utils::set_yaml_path;
+    use serde_json::json;
+
+    fn changes(deployed: Value, desired: Value, skip: &[&str]) -> Vec<String> {
+        let skip: Vec<String> = skip.iter().map(|path| path.to_string()).collect();
+        let mut changes = vec![];
+        diff_values("", &deployed, &desired, &skip, &mut changes);
+        changes
+    }
+
+    #[test]
+    fn diff_nested_values() {
+        assert_eq!(
+            changes(
+                json!({"a": {"b": 1, "c": "x"}, "d": [1, 2], "e": true}),
+                json!({"a": {"b": 2, "c": "x"}, "d": [1, 3], "f": "new"}),
+                &[],
+            ),
+            vec![
+                "a.b changed",
+                "d[1] changed",
+                "e added outside rops",
+                "f not deployed"
+            ]
+        );
+    }
+
+    #[test]
+    fn diff_typed_scalars() {
+        assert_eq!(
+            changes(json!({"port": 80}), json!({"port": "80"}), &[]),
+            vec!["port changed"]
+        );
+        assert!(changes(json!({"ratio": 1}), json!({"ratio": 1.0}), &[]).is_empty());
+        assert_eq!(
+            changes(json!({"list": [1]}), json!({"list": [1, 2]}), &[]),
+            vec!["list changed"]
+        );
+    }
+
+    #[test]
+    fn diff_numeric_looking_string_tag() {
+        // deployed with `--set image.tag=1.10`, helm keeps the string
+        let deployed = json!({"image": {"tag": "1.10"}, "replicas": 2});
+        let mut desired = serde_yaml::Value::Null;
+        set_yaml_path(&mut desired, "image.tag", "1.10");
+        set_yaml_path(&mut desired, "replicas", "2");
+        let desired = serde_json::to_value(desired).unwrap();
+        assert!(changes(deployed.clone(), desired, &[]).is_empty());
+
+        let mut desired = serde_yaml::Value::Null;
+        set_yaml_path(&mut desired, "image.tag", "1.1");
+        set_yaml_path(&mut desired, "replicas", "2");
+        let desired = serde_json::to_value(desired).unwrap();
+        assert_eq!(changes(deployed, desired, &[]), vec!["image.tag changed"]);
+    }
+
+    #[test]
+    fn diff_skips_paths() {
+        assert!(
+            changes(
+                json!({"image": {"tag": "1"}}),
+                json!({"image": {"tag": "2"}}),
+                &["image.tag"],
+            )
+            .is_empty()
+        );
+    }
+
+    #[test]
+    fn value_at_path() {
+        let value = json!({"image": {"tag": "1.0", "empty": null}});
+        assert_eq!(value_at(&value, "image.tag"), Some(&json!("1.0")));
+        assert_eq!(value_at(&value, "image.empty"), None);
+        assert_eq!(value_at(&value, "image.missing"), None);
+    }
+}
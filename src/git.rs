@@ -1,10 +1,11 @@
 use crate::{
     error::{RopsError, RopsResult},
     settings::Settings,
-    utils::{Secret, StreamCommand, rimraf},
+    utils::{Secret, StreamCommand, hashed_dns_label, rimraf},
 };
-use reqwest::blocking::Client;
+use reqwest::{Url, blocking::Client};
 use serde::{Deserialize, Serialize};
+use std::path::{Path, PathBuf};
 use std::process::Command;
 
 #[derive(Debug, Default, Clone, Deserialize, Serialize)]
@@ -17,6 +18,61 @@ pub struct GitSettings {
     pub sha: String,
     #[serde(default = "GitSettings::get_github_token", skip_deserializing)]
     pub github_token: Option<Secret>,
+    /// directory of the bare mirrors of chart git repos
+    pub cache_dir: Option<String>,
+}
+
+/// A git repo required by a chart - a url or a table with the ref to checkout
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(from = "GitRepoEntry")]
+pub struct GitRepo {
+    pub url: String,
+    /// branch, tag or commit to checkout - the remote HEAD if not given
+    #[serde(rename = "ref", skip_serializing_if = "Option::is_none")]
+    pub git_ref: Option<String>,
+    /// checkout path - the repo name if not given
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub path: Option<String>,
+    /// fetch depth of the mirror - full history if not given
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub depth: Option<u32>,
+}
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum GitRepoEntry {
+    Url(String),
+    Table {
+        url: String,
+        #[serde(rename = "ref")]
+        git_ref: Option<String>,
+        path: Option<String>,
+        depth: Option<u32>,
+    },
+}
+
+impl From<GitRepoEntry> for GitRepo {
+    fn from(entry: GitRepoEntry) -> Self {
+        match entry {
+            GitRepoEntry::Url(url) => Self {
+                url,
+                git_ref: None,
+                path: None,
+                depth: None,
+            },
+            GitRepoEntry::Table {
+                url,
+                git_ref,
+                path,
+                depth,
+            } => Self {
+                url,
+                git_ref,
+                path,
+                depth,
+            },
+        }
+    }
 }
 
 #[derive(Clone, Debug)]
@@ -127,19 +183,245 @@ impl GitSettings {
         "".to_string()
     }
 
-    pub fn clone_repo(repo_name: &str, repo: &str) -> RopsResult<()> {
-        rimraf(repo_name)?;
+    /// The current user - the git user email or the `USER` environment variable
+    pub fn get_user() -> String {
+        match Command::new("git").arg("config").arg("user.email").output() {
+            Ok(output) if output.status.success() && !output.stdout.is_empty() => {
+                String::from_utf8_lossy(&output.stdout).trim().to_string()
+            }
+            _ => std::env::var("USER").unwrap_or_else(|_| "unknown".to_string()),
+        }
+    }
+
+    /// Paths with uncommitted changes in the worktree, including untracked files
+    pub fn uncommitted_changes() -> RopsResult<Vec<String>> {
+        let output = Command::new("git")
+            .arg("status")
+            .arg("--porcelain")
+            .output()?;
+        if !output.status.success() {
+            return Err(RopsError::GitError(format!(
+                "Failed to get git status: {}",
+                String::from_utf8_lossy(&output.stderr).trim()
+            )));
+        }
+        Ok(String::from_utf8_lossy(&output.stdout)
+            .lines()
+            .map(|line| line.get(3..).unwrap_or(line).to_string())
+            .collect())
+    }
+
+    /// Tags pointing at the current commit
+    pub fn head_tags() -> RopsResult<Vec<String>> {
+        let output = Command::new("git")
+            .arg("tag")
+            .arg("--points-at")
+            .arg("HEAD")
+            .output()?;
+        if !output.status.success() {
+            return Err(RopsError::GitError(format!(
+                "Failed to get git tags: {}",
+                String::from_utf8_lossy(&output.stderr).trim()
+            )));
+        }
+        Ok(String::from_utf8_lossy(&output.stdout)
+            .lines()
+            .map(str::to_string)
+            .collect())
+    }
+
+    /// Check if a string looks like a git repository url
+    ///
+    /// Supports urls with a scheme (https, ssh, git, file) and scp-like urls (git@host:path)
+    pub fn is_repo_url(repo: &str) -> bool {
+        match Url::parse(repo) {
+            Ok(url) => {
+                ["https", "http", "ssh", "git", "file"].contains(&url.scheme())
+                    && (url.scheme() == "file" || url.host_str().is_some())
+            }
+            Err(_) => match repo.split_once(':') {
+                Some((user_host, path)) => {
+                    !user_host.is_empty()
+                        && !user_host.contains('/')
+                        && !path.is_empty()
+                        && !path.starts_with("//")
+                }
+                None => false,
+            },
+        }
+    }
+
+    /// Check if a git repository is reachable with `git ls-remote`
+    pub fn is_reachable(repo: &str) -> bool {
+        Command::new("git")
+            .arg("ls-remote")
+            .arg("--quiet")
+            .arg("--exit-code")
+            .arg(repo)
+            .arg("HEAD")
+            .output()
+            .map(|output| output.status.success())
+            .unwrap_or(false)
+    }
+
+    /// Directory of the bare mirrors of chart git repos
+    ///
+    /// The `cache_dir` setting, `ROPS_GIT_CACHE` or `~/.cache/rops/git`
+    pub fn get_cache_dir(&self) -> RopsResult<PathBuf> {
+        if let Some(cache_dir) = self.cache_dir.as_ref() {
+            return Ok(PathBuf::from(cache_dir));
+        }
+        if let Ok(cache_dir) = std::env::var("ROPS_GIT_CACHE") {
+            return Ok(PathBuf::from(cache_dir));
+        }
+        match std::env::home_dir() {
+            Some(home) => Ok(home.join(".cache").join("rops").join("git")),
+            None => Err(RopsError::Error("Failed to get home directory".into())),
+        }
+    }
+
+    /// Checkout a git repo into its path
+    ///
+    /// The repo is fetched incrementally into a bare mirror in the cache directory
+    /// and the pinned ref is checked out detached from the mirror. Fails if a ref
+    /// pinning a commit resolves to another commit, e.g. a branch named like a SHA.
+    pub fn checkout_repo(&self, repo_name: &str, repo: &GitRepo) -> RopsResult<()> {
+        let mirror = self.get_cache_dir()?.join(mirror_name(&repo.url));
+        let path = repo.path.as_deref().unwrap_or(repo_name);
+        Self::fetch_mirror(&mirror, repo)?;
+        // git records the absolute path of a local origin, the cache dir may be relative
+        let mirror = mirror.canonicalize()?;
+        let git_ref = repo.git_ref.as_deref().unwrap_or("refs/rops/HEAD");
+        let commit = Self::rev_parse(&mirror, &format!("{git_ref}^{{commit}}")).map_err(|_| {
+            let hint = if is_commit(git_ref) && git_ref.len() < 40 {
+                " - pin commits not on a branch or tag with their full SHA"
+            } else {
+                ""
+            };
+            RopsError::Error(format!(
+                "Git ref '{git_ref}' not found in '{}'{hint}",
+                repo.url
+            ))
+        })?;
+        if is_commit(git_ref) && !commit.starts_with(&git_ref.to_lowercase()) {
+            return Err(RopsError::Error(format!(
+                "Git ref '{git_ref}' of '{}' resolves to {commit}, not the pinned commit",
+                repo.url
+            )));
+        }
+        let origin = Self::git_output(Path::new(path), &["remote", "get-url", "origin"])
+            .ok()
+            .and_then(|origin| Path::new(&origin).canonicalize().ok());
+        if origin.as_deref() != Some(mirror.as_path()) {
+            rimraf(path)?;
+            let mut child = Command::new("git");
+            child
+                .arg("clone")
+                .arg("--shared")
+                .arg("--no-checkout")
+                .arg(&mirror)
+                .arg(path);
+            Self::run_git(child, &format!("clone '{}' into '{path}'", repo.url))?;
+        } else {
+            let mut child = Command::new("git");
+            child
+                .arg("-C")
+                .arg(path)
+                .arg("fetch")
+                .arg("--quiet")
+                .arg("origin");
+            Self::run_git(child, &format!("fetch '{}' into '{path}'", repo.url))?;
+        }
+        let mut child = Command::new("git");
+        child
+            .arg("-C")
+            .arg(path)
+            .arg("checkout")
+            .arg("--quiet")
+            .arg("--force")
+            .arg("--detach")
+            .arg(&commit);
+        Self::run_git(child, &format!("checkout '{git_ref}' of '{}'", repo.url))?;
+        log::info!(
+            "Checked out '{git_ref}' of '{}' at {commit} into '{path}'",
+            repo.url
+        );
+        Ok(())
+    }
+
+    /// Create or incrementally fetch the bare mirror of a git repo
+    fn fetch_mirror(mirror: &Path, repo: &GitRepo) -> RopsResult<()> {
+        if !mirror.join("HEAD").exists() {
+            std::fs::create_dir_all(mirror)?;
+            let mut child = Command::new("git");
+            child.arg("init").arg("--quiet").arg("--bare").arg(mirror);
+            Self::run_git(child, &format!("create the mirror of '{}'", repo.url))?;
+        }
         let mut child = Command::new("git");
-        child.arg("clone").arg(repo).arg(repo_name);
-        if StreamCommand::new(child).run()? {
-            Ok(())
+        child
+            .arg("-C")
+            .arg(mirror)
+            .arg("fetch")
+            .arg("--quiet")
+            .arg("--prune")
+            .arg("--force");
+        if let Some(depth) = repo.depth {
+            child.arg(format!("--depth={depth}"));
+        }
+        child
+            .arg(&repo.url)
+            .arg("+HEAD:refs/rops/HEAD")
+            .arg("+refs/heads/*:refs/heads/*")
+            .arg("+refs/tags/*:refs/tags/*");
+        Self::run_git(child, &format!("fetch '{}'", repo.url))?;
+        // a commit not on a fetched branch or tag must be fetched explicitly, which
+        // remotes only allow for full SHAs
+        if let Some(git_ref) = repo.git_ref.as_ref()
+            && git_ref.len() == 40
+            && is_commit(git_ref)
+            && Self::rev_parse(mirror, &format!("{git_ref}^{{commit}}")).is_err()
+        {
+            let mut child = Command::new("git");
+            child
+                .arg("-C")
+                .arg(mirror)
+                .arg("fetch")
+                .arg("--quiet")
+                .arg(&repo.url)
+                .arg(git_ref);
+            Self::run_git(child, &format!("fetch '{git_ref}' of '{}'", repo.url))?;
+        }
+        Ok(())
+    }
+
+    fn rev_parse(repo: &Path, rev: &str) -> RopsResult<String> {
+        Self::git_output(repo, &["rev-parse", "--verify", "--quiet", rev])
+    }
+
+    fn git_output(repo: &Path, args: &[&str]) -> RopsResult<String> {
+        let output = Command::new("git")
+            .arg("-C")
+            .arg(repo)
+            .args(args)
+            .output()?;
+        if output.status.success() {
+            Ok(String::from_utf8_lossy(&output.stdout).trim().to_string())
         } else {
             Err(RopsError::Error(format!(
-                "Failed to clone Git repo '{}' into '{}'",
-                repo, repo_name
+                "git {} failed: {}",
+                args.join(" "),
+                String::from_utf8_lossy(&output.stderr).trim()
             )))
         }
     }
+
+    fn run_git(command: Command, action: &str) -> RopsResult<()> {
+        if StreamCommand::new(command).run()? {
+            Ok(())
+        } else {
+            Err(RopsError::Error(format!("Failed to {action}")))
+        }
+    }
 }
 
 impl GithubDownloadRelease {
@@ -271,3 +553,92 @@ impl GithubDownloadRelease {
         Ok(asset)
     }
 }
+
+/// Check if a ref looks like a full or abbreviated commit SHA
+fn is_commit(git_ref: &str) -> bool {
+    git_ref.len() >= 7 && git_ref.len() <= 40 && git_ref.chars().all(|c| c.is_ascii_hexdigit())
+}
+
+/// Directory name of the bare mirror of a repo url - urls differing only in characters
+/// invalid in labels, such as `org/a_b` and `org/a-b`, get distinct names
+fn mirror_name(url: &str) -> String {
+    format!("{}.git", hashed_dns_label(url, 100))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn mirror_names() {
+        let name = mirror_name("https://github.com/org/a_b.git");
+        assert!(name.starts_with("https-github-com-org-a-b-git-"), "{name}");
+        assert!(name.ends_with(".git"));
+        assert_ne!(name, mirror_name("https://github.com/org/a-b.git"));
+        assert_eq!(name, mirror_name("https://github.com/org/a_b.git"));
+    }
+
+    fn git(dir: &Path, args: &[&str]) -> String {
+        let output = Command::new("git")
+            .args(["-c", "user.name=rops", "-c", "user.email=rops@localhost"])
+            .arg("-C")
+            .arg(dir)
+            .args(args)
+            .output()
+            .unwrap();
+        assert!(output.status.success(), "git {args:?} failed");
+        String::from_utf8(output.stdout).unwrap().trim().into()
+    }
+
+    fn commit(dir: &Path, content: &str) -> String {
+        std::fs::write(dir.join("version"), content).unwrap();
+        git(dir, &["add", "version"]);
+        git(dir, &["commit", "-q", "-m", content]);
+        git(dir, &["rev-parse", "HEAD"])
+    }
+
+    #[test]
+    fn checkout_refs_from_mirror() {
+        let tmp = tempfile::tempdir().unwrap();
+        let source = tmp.path().join("source");
+        std::fs::create_dir_all(&source).unwrap();
+        git(&source, &["init", "-q", "-b", "main"]);
+        let first = commit(&source, "v1");
+        git(&source, &["tag", "v1"]);
+        commit(&source, "v2");
+        let url = tmp.path().join("remote.git").to_string_lossy().to_string();
+        git(tmp.path(), &["clone", "-q", "--bare", "source", &url]);
+
+        // a cache dir which is not canonical, as git records the canonical origin
+        std::fs::create_dir_all(tmp.path().join("x")).unwrap();
+        let settings = GitSettings {
+            cache_dir: Some(tmp.path().join("x/../cache").to_string_lossy().to_string()),
+            ..Default::default()
+        };
+        let path = tmp.path().join("checkout");
+        let checkout = |git_ref: Option<&str>| {
+            let repo = GitRepo {
+                url: url.clone(),
+                git_ref: git_ref.map(String::from),
+                path: Some(path.to_string_lossy().to_string()),
+                depth: None,
+            };
+            settings
+                .checkout_repo("remote", &repo)
+                .map(|_| std::fs::read_to_string(path.join("version")).unwrap())
+        };
+
+        assert_eq!(checkout(None).unwrap(), "v2");
+        std::fs::write(path.join("marker"), "").unwrap();
+        assert_eq!(checkout(Some("v1")).unwrap(), "v1");
+        assert_eq!(checkout(Some(&first)).unwrap(), "v1");
+        assert_eq!(checkout(Some(&first[..7])).unwrap(), "v1");
+        assert_eq!(checkout(Some("main")).unwrap(), "v2");
+        // the checkout is reused instead of cloned again
+        assert!(path.join("marker").exists());
+
+        let err = checkout(Some("abcdef1")).unwrap_err().to_string();
+        assert!(err.contains("full SHA"), "{err}");
+        assert!(checkout(Some("missing")).is_err());
+    }
+}
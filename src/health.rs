@@ -0,0 +1,160 @@
+use crate::{error::RopsResult, kube::KubeClient};
+use reqwest::blocking::Client;
+use serde::{Deserialize, Serialize};
+use std::time::{Duration, Instant};
+
+/// Post-deploy health check of a chart release
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct HealthCheck {
+    /// seconds to wait for rollouts and the url to become healthy
+    #[serde(default = "HealthCheck::get_default_timeout")]
+    pub timeout: u64,
+    /// wait for the rollout of the release deployments and statefulsets
+    #[serde(default = "crate::utils::as_true")]
+    pub rollout: bool,
+    /// optional url which must return a success status - it can contain the
+    /// `{env}`, `{namespace}` and `{release}` placeholders
+    pub url: Option<String>,
+    /// rollback the release to the previous revision when the check fails
+    #[serde(default = "crate::utils::as_true")]
+    pub rollback: bool,
+}
+
+impl HealthCheck {
+    fn get_default_timeout() -> u64 {
+        300
+    }
+
+    /// Run the health check and return the list of failures
+    ///
+    /// Workloads of the release are selected with the label selector
+    pub fn verify(
+        &self,
+        release: &str,
+        selector: &str,
+        namespace: &str,
+        env: &str,
+    ) -> RopsResult<Vec<String>> {
+        let mut failures = vec![];
+        if self.rollout {
+            failures.extend(self.wait_rollout(
+                &KubeClient::from_kubeconfig()?,
+                release,
+                selector,
+                namespace,
+            )?);
+        }
+        if let Some(url) = self.url.as_ref() {
+            let url = url
+                .replace("{env}", env)
+                .replace("{namespace}", namespace)
+                .replace("{release}", release);
+            if let Err(failure) = self.probe(&url) {
+                failures.push(failure);
+            }
+        }
+        Ok(failures)
+    }
+
+    /// Poll the deployments and statefulsets of a release until they are rolled out
+    ///
+    /// Returns the workloads which did not complete their rollout before the timeout
+    fn wait_rollout(
+        &self,
+        client: &KubeClient,
+        release: &str,
+        selector: &str,
+        namespace: &str,
+    ) -> RopsResult<Vec<String>> {
+        let deadline = Instant::now() + Duration::from_secs(self.timeout);
+        loop {
+            let pending: Vec<_> = client
+                .workloads(namespace, selector)
+                .map_err(|err| format!("Failed to list workloads of release '{release}': {err}"))?
+                .into_iter()
+                .filter(|workload| !workload.rolled_out)
+                .collect();
+            if pending.is_empty() {
+                log::info!("Rollout of release '{release}' complete");
+                return Ok(vec![]);
+            }
+            for workload in pending.iter() {
+                log::info!(
+                    "Waiting for rollout of {workload}: {} of {} updated, {} ready",
+                    workload.updated,
+                    workload.replicas,
+                    workload.ready
+                );
+            }
+            if Instant::now() >= deadline {
+                Self::log_pod_failures(client, selector, namespace)?;
+                return Ok(pending
+                    .iter()
+                    .map(|workload| format!("rollout of {workload} did not complete"))
+                    .collect());
+            }
+            std::thread::sleep(Duration::from_secs(5));
+        }
+    }
+
+    /// Log the pods of a release which are not ready with their warning events
+    fn log_pod_failures(client: &KubeClient, selector: &str, namespace: &str) -> RopsResult<()> {
+        let pods: Vec<String> = client
+            .pods(namespace, selector)?
+            .into_iter()
+            .filter(|pod| !pod.ready)
+            .map(|pod| {
+                log::warn!(
+                    "Pod {} is not ready - phase {}, {} restarts",
+                    pod.name,
+                    pod.phase,
+                    pod.restarts
+                );
+                pod.name
+            })
+            .collect();
+        for event in client.events(namespace, Some(&pods))? {
+            if event.event_type == "Warning" {
+                log::warn!(
+                    "{}/{}: {} {} (x{})",
+                    event.kind,
+                    event.name,
+                    event.reason,
+                    event.message,
+                    event.count
+                );
+            }
+        }
+        Ok(())
+    }
+
+    /// Poll the url until it returns a success status or the timeout expires
+    fn probe(&self, url: &str) -> Result<(), String> {
+        let client = Client::builder()
+            .timeout(Duration::from_secs(10))
+            .build()
+            .map_err(|err| err.to_string())?;
+        let deadline = Instant::now() + Duration::from_secs(self.timeout);
+        log::info!("Probing {url}");
+        loop {
+            let failure = match client
+                .get(url)
+                .header("User-Agent", "quantmind/rops")
+                .send()
+            {
+                Ok(response) if response.status().is_success() => {
+                    log::info!("{url} is healthy - status {}", response.status());
+                    return Ok(());
+                }
+                Ok(response) => format!("{url} returned status {}", response.status()),
+                Err(err) => format!("{url} is not reachable: {err}"),
+            };
+            if Instant::now() >= deadline {
+                return Err(failure);
+            }
+            log::debug!("{failure} - retrying");
+            std::thread::sleep(Duration::from_secs(5));
+        }
+    }
+}
@@ -0,0 +1,286 @@
+use crate::{
+    charts::Chart,
+    error::{RopsError, RopsResult},
+    utils::StreamCommand,
+};
+use serde::Deserialize;
+use std::{
+    collections::{BTreeMap, HashMap},
+    path::PathBuf,
+    process::Command,
+    time::Duration,
+};
+
+#[derive(clap::Subcommand, Debug, Clone)]
+pub enum ReposCommand {
+    /// Add and update the helm repos of all charts and refresh their indexes
+    Sync {
+        /// Only show the repos which would be changed
+        #[arg(long, action = clap::ArgAction::SetTrue)]
+        dry_run: Option<bool>,
+    },
+}
+
+/// State of a helm repo required by a chart
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RepoState {
+    Missing,
+    /// added with a different url
+    Changed,
+    /// index older than the ttl
+    Stale,
+    Fresh,
+}
+
+#[derive(Debug, Deserialize)]
+struct InstalledRepo {
+    name: String,
+    url: String,
+}
+
+/// The helm repos installed locally
+pub struct HelmRepos {
+    installed: HashMap<String, String>,
+    /// the helm repository cache holding the repo indexes
+    cache: Option<PathBuf>,
+}
+
+impl ReposCommand {
+    pub fn run(&self, charts: &HashMap<String, Chart>) -> RopsResult<()> {
+        match self {
+            Self::Sync { dry_run } => {
+                let repos = HelmRepos::chart_repos(charts.values())?;
+                HelmRepos::load()?.sync(&repos, None, dry_run.unwrap_or(false))
+            }
+        }
+    }
+}
+
+impl HelmRepos {
+    /// Load the installed repos with `helm repo list`
+    pub fn load() -> RopsResult<Self> {
+        let output = Command::new("helm")
+            .arg("repo")
+            .arg("list")
+            .arg("--output")
+            .arg("json")
+            .output()?;
+        let installed = if output.status.success() {
+            serde_json::from_slice::<Vec<InstalledRepo>>(&output.stdout)?
+                .into_iter()
+                .map(|repo| (repo.name, repo.url))
+                .collect()
+        } else {
+            let stderr = String::from_utf8_lossy(&output.stderr);
+            // helm fails when there are no repos
+            if !stderr.contains("no repositories") {
+                return Err(RopsError::Error(format!(
+                    "Failed to list helm repos: {}",
+                    stderr.trim()
+                )));
+            }
+            HashMap::new()
+        };
+        let cache = Command::new("helm")
+            .arg("env")
+            .arg("HELM_REPOSITORY_CACHE")
+            .output()
+            .ok()
+            .filter(|output| output.status.success())
+            .map(|output| String::from_utf8_lossy(&output.stdout).trim().to_string())
+            .filter(|cache| !cache.is_empty())
+            .map(PathBuf::from);
+        Ok(Self { installed, cache })
+    }
+
+    /// The non OCI helm repos of charts - fails if a repo name is used with different urls
+    pub fn chart_repos<'a>(
+        charts: impl Iterator<Item = &'a Chart>,
+    ) -> RopsResult<BTreeMap<String, String>> {
+        let mut repos = BTreeMap::new();
+        for chart in charts {
+            for (name, url) in chart.helm_repos.iter() {
+                if url.starts_with("oci://") {
+                    continue;
+                }
+                match repos.insert(name.clone(), url.clone()) {
+                    Some(other) if &other != url => {
+                        return Err(RopsError::Error(format!(
+                            "Helm repo '{name}' is used with different urls '{other}' and '{url}'"
+                        )));
+                    }
+                    _ => {}
+                }
+            }
+        }
+        Ok(repos)
+    }
+
+    /// State of a repo - stale if its index is older than the ttl
+    pub fn state(&self, name: &str, url: &str, ttl: Option<Duration>) -> RepoState {
+        match self.installed.get(name) {
+            None => RepoState::Missing,
+            Some(installed) if installed.trim_end_matches('/') != url.trim_end_matches('/') => {
+                RepoState::Changed
+            }
+            Some(_) => {
+                let age = self
+                    .cache
+                    .as_ref()
+                    .and_then(|cache| cache.join(format!("{name}-index.yaml")).metadata().ok())
+                    .and_then(|metadata| metadata.modified().ok())
+                    .and_then(|modified| modified.elapsed().ok());
+                match (age, ttl) {
+                    (Some(age), Some(ttl)) if age <= ttl => RepoState::Fresh,
+                    _ => RepoState::Stale,
+                }
+            }
+        }
+    }
+
+    /// Add missing repos, update changed urls and refresh stale indexes with a single update
+    ///
+    /// Without a ttl all indexes are refreshed
+    pub fn sync(
+        &mut self,
+        repos: &BTreeMap<String, String>,
+        ttl: Option<Duration>,
+        dry_run: bool,
+    ) -> RopsResult<()> {
+        let mut update = vec![];
+        for (name, url) in repos.iter() {
+            let state = self.state(name, url, ttl);
+            if matches!(state, RepoState::Missing | RepoState::Changed) {
+                let mut command = Command::new("helm");
+                command.arg("repo").arg("add").arg(name).arg(url);
+                if state == RepoState::Changed {
+                    log::warn!(
+                        "Helm repo '{name}' url changed from '{}' to '{url}'",
+                        self.installed[name]
+                    );
+                    command.arg("--force-update");
+                }
+                if !StreamCommand::new(command).with_dry_run(dry_run).run()? {
+                    return Err(RopsError::Error(format!(
+                        "Failed to add Helm repo '{name}'"
+                    )));
+                }
+                self.installed.insert(name.clone(), url.clone());
+            }
+            // adding a repo downloads its index
+            if state == RepoState::Stale {
+                update.push(name.as_str());
+            }
+        }
+        if update.is_empty() {
+            log::info!("Helm repos are up to date");
+            return Ok(());
+        }
+        let mut command = Command::new("helm");
+        command.arg("repo").arg("update").args(&update);
+        if StreamCommand::new(command).with_dry_run(dry_run).run()? {
+            Ok(())
+        } else {
+            Err(RopsError::Error(format!(
+                "Failed to update Helm repos {}",
+                update.join(", ")
+            )))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{fs::File, time::SystemTime};
+
+    fn chart(repos: &[(&str, &str)]) -> Chart {
+        Chart {
+            helm_repos: repos
+                .iter()
+                .map(|(name, url)| (name.to_string(), url.to_string()))
+                .collect(),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn chart_repos_merged() {
+        let charts = [
+            chart(&[("bitnami", "https://charts.bitnami.com/bitnami")]),
+            chart(&[
+                ("bitnami", "https://charts.bitnami.com/bitnami"),
+                ("jetstack", "https://charts.jetstack.io"),
+                ("ghcr", "oci://ghcr.io/org/charts"),
+            ]),
+        ];
+        let repos = HelmRepos::chart_repos(charts.iter()).unwrap();
+        assert_eq!(
+            repos.into_iter().collect::<Vec<_>>(),
+            [
+                (
+                    "bitnami".into(),
+                    "https://charts.bitnami.com/bitnami".into()
+                ),
+                ("jetstack".into(), "https://charts.jetstack.io".into()),
+            ]
+        );
+
+        let charts = [
+            chart(&[("bitnami", "https://charts.bitnami.com/bitnami")]),
+            chart(&[("bitnami", "https://example.com/bitnami")]),
+        ];
+        let err = HelmRepos::chart_repos(charts.iter()).unwrap_err();
+        assert!(err.to_string().contains("different urls"), "{err}");
+    }
+
+    #[test]
+    fn repo_states() {
+        let cache = tempfile::tempdir().unwrap();
+        let repos = HelmRepos {
+            installed: [
+                ("fresh", "https://fresh.example.com/"),
+                ("stale", "https://stale.example.com"),
+                ("moved", "https://old.example.com"),
+                ("unsynced", "https://unsynced.example.com"),
+            ]
+            .into_iter()
+            .map(|(name, url)| (name.to_string(), url.to_string()))
+            .collect(),
+            cache: Some(cache.path().to_path_buf()),
+        };
+        File::create(cache.path().join("fresh-index.yaml")).unwrap();
+        File::create(cache.path().join("stale-index.yaml"))
+            .unwrap()
+            .set_modified(SystemTime::now() - Duration::from_secs(7200))
+            .unwrap();
+        let ttl = Some(Duration::from_secs(3600));
+
+        let state = |name, url| repos.state(name, url, ttl);
+        assert_eq!(
+            state("fresh", "https://fresh.example.com"),
+            RepoState::Fresh
+        );
+        assert_eq!(
+            state("stale", "https://stale.example.com"),
+            RepoState::Stale
+        );
+        assert_eq!(
+            state("moved", "https://new.example.com"),
+            RepoState::Changed
+        );
+        assert_eq!(
+            state("unsynced", "https://unsynced.example.com"),
+            RepoState::Stale
+        );
+        assert_eq!(
+            state("other", "https://other.example.com"),
+            RepoState::Missing
+        );
+        // without a ttl every index is refreshed
+        assert_eq!(
+            repos.state("fresh", "https://fresh.example.com", None),
+            RepoState::Stale
+        );
+    }
+}
@@ -0,0 +1,907 @@
+use crate::{
+    error::{RopsError, RopsResult},
+    utils::Secret,
+};
+use base64::{Engine as _, engine::general_purpose};
+use reqwest::{
+    Certificate, Identity, Method, StatusCode,
+    blocking::{Client, RequestBuilder, Response},
+};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::{
+    collections::HashMap,
+    path::{Path, PathBuf},
+    process::Command,
+    sync::Mutex,
+    time::Duration,
+};
+
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// A minimal Kubernetes API client configured from the current kubeconfig context
+pub struct KubeClient {
+    pub server: String,
+    client: Client,
+    token: Option<Secret>,
+    /// discovered resources by api version
+    resources: Mutex<HashMap<String, Vec<ApiResource>>>,
+}
+
+/// A resource served by the API, from the discovery of its api version
+#[derive(Debug, Clone, Deserialize)]
+pub struct ApiResource {
+    /// plural name used in paths, e.g. `deployments`
+    pub name: String,
+    pub kind: String,
+    #[serde(default)]
+    pub namespaced: bool,
+}
+
+#[derive(Debug, Default, Deserialize)]
+struct KubeConfig {
+    #[serde(default, rename = "current-context")]
+    current_context: String,
+    #[serde(default)]
+    clusters: Vec<NamedCluster>,
+    #[serde(default)]
+    contexts: Vec<NamedContext>,
+    #[serde(default)]
+    users: Vec<NamedUser>,
+}
+
+#[derive(Debug, Deserialize)]
+struct NamedCluster {
+    name: String,
+    cluster: KubeCluster,
+}
+
+#[derive(Debug, Deserialize)]
+struct KubeCluster {
+    server: String,
+    #[serde(rename = "certificate-authority")]
+    certificate_authority: Option<String>,
+    #[serde(rename = "certificate-authority-data")]
+    certificate_authority_data: Option<String>,
+    #[serde(default, rename = "insecure-skip-tls-verify")]
+    insecure_skip_tls_verify: bool,
+}
+
+#[derive(Debug, Deserialize)]
+struct NamedContext {
+    name: String,
+    context: KubeContext,
+}
+
+#[derive(Debug, Deserialize)]
+struct KubeContext {
+    cluster: String,
+    #[serde(default)]
+    user: String,
+}
+
+#[derive(Debug, Deserialize)]
+struct NamedUser {
+    name: String,
+    #[serde(default)]
+    user: KubeUser,
+}
+
+#[derive(Debug, Default, Deserialize)]
+struct KubeUser {
+    token: Option<String>,
+    #[serde(rename = "tokenFile")]
+    token_file: Option<String>,
+    #[serde(rename = "client-certificate")]
+    client_certificate: Option<String>,
+    #[serde(rename = "client-certificate-data")]
+    client_certificate_data: Option<String>,
+    #[serde(rename = "client-key")]
+    client_key: Option<String>,
+    #[serde(rename = "client-key-data")]
+    client_key_data: Option<String>,
+    exec: Option<ExecConfig>,
+}
+
+/// Credential plugin of a kubeconfig user, e.g. `aws eks get-token`
+#[derive(Debug, Deserialize)]
+struct ExecConfig {
+    command: String,
+    #[serde(default)]
+    args: Vec<String>,
+    #[serde(default)]
+    env: Option<Vec<ExecEnv>>,
+}
+
+#[derive(Debug, Deserialize)]
+struct ExecEnv {
+    name: String,
+    value: String,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct ExecCredentialStatus {
+    token: Option<String>,
+    client_certificate_data: Option<String>,
+    client_key_data: Option<String>,
+}
+
+/// A deployment or statefulset
+#[derive(Debug, Clone, Serialize)]
+pub struct Workload {
+    pub kind: String,
+    pub name: String,
+    pub replicas: u64,
+    pub ready: u64,
+    pub updated: u64,
+    /// the controller has processed the latest spec and all replicas are updated and ready
+    pub rolled_out: bool,
+}
+
+/// A pod with its readiness
+#[derive(Debug, Clone, Serialize)]
+pub struct Pod {
+    pub name: String,
+    pub phase: String,
+    pub ready: bool,
+    pub restarts: u64,
+    pub containers: Vec<String>,
+    pub created: String,
+}
+
+/// An event of an object
+#[derive(Debug, Clone, Serialize)]
+pub struct Event {
+    pub kind: String,
+    pub name: String,
+    pub event_type: String,
+    pub reason: String,
+    pub message: String,
+    pub count: u64,
+    pub last_seen: String,
+}
+
+impl KubeClient {
+    /// Create a client for the current context of `KUBECONFIG` or `~/.kube/config`
+    pub fn from_kubeconfig() -> RopsResult<Self> {
+        let path = Self::kubeconfig_path()?;
+        let content = std::fs::read_to_string(&path).map_err(|err| {
+            RopsError::Error(format!(
+                "Failed to read kubeconfig '{}': {err}",
+                path.display()
+            ))
+        })?;
+        Self::from_config(&content, &path)
+    }
+
+    /// Create a client for the current context of a kubeconfig - relative files are
+    /// resolved from the directory of its path
+    fn from_config(content: &str, path: &Path) -> RopsResult<Self> {
+        let config: KubeConfig = serde_yaml::from_str(content)?;
+        let base = path.parent().unwrap_or(Path::new("."));
+        let context = config
+            .contexts
+            .iter()
+            .find(|context| context.name == config.current_context)
+            .map(|context| &context.context)
+            .ok_or_else(|| {
+                RopsError::Error(format!(
+                    "Context '{}' not found in kubeconfig '{}'",
+                    config.current_context,
+                    path.display()
+                ))
+            })?;
+        let cluster = config
+            .clusters
+            .iter()
+            .find(|cluster| cluster.name == context.cluster)
+            .map(|cluster| &cluster.cluster)
+            .ok_or_else(|| {
+                RopsError::Error(format!(
+                    "Cluster '{}' not found in kubeconfig '{}'",
+                    context.cluster,
+                    path.display()
+                ))
+            })?;
+        let default_user = KubeUser::default();
+        let user = config
+            .users
+            .iter()
+            .find(|user| user.name == context.user)
+            .map(|user| &user.user)
+            .unwrap_or(&default_user);
+
+        let mut builder = Client::builder()
+            // log streams are not bounded, other requests set their own timeout
+            .timeout(None)
+            .danger_accept_invalid_certs(cluster.insecure_skip_tls_verify);
+        if let Some(ca) = read_data(
+            base,
+            cluster.certificate_authority.as_deref(),
+            cluster.certificate_authority_data.as_deref(),
+        )? {
+            builder = builder.add_root_certificate(Certificate::from_pem(&ca)?);
+        }
+        let mut token = match (user.token.as_ref(), user.token_file.as_ref()) {
+            (Some(token), _) => Some(token.clone()),
+            (None, Some(file)) => {
+                Some(std::fs::read_to_string(base.join(file))?.trim().to_string())
+            }
+            (None, None) => None,
+        };
+        let mut certificate = read_data(
+            base,
+            user.client_certificate.as_deref(),
+            user.client_certificate_data.as_deref(),
+        )?;
+        let mut key = read_data(
+            base,
+            user.client_key.as_deref(),
+            user.client_key_data.as_deref(),
+        )?;
+        if let Some(exec) = user.exec.as_ref() {
+            let status = Self::exec_credential(exec)?;
+            token = status.token.or(token);
+            if let (Some(exec_certificate), Some(exec_key)) =
+                (status.client_certificate_data, status.client_key_data)
+            {
+                certificate = Some(exec_certificate.into_bytes());
+                key = Some(exec_key.into_bytes());
+            }
+        }
+        if let (Some(mut certificate), Some(key)) = (certificate, key) {
+            certificate.push(b'\n');
+            certificate.extend(key);
+            builder = builder.identity(Identity::from_pem(&certificate)?);
+        }
+        Ok(Self {
+            server: cluster.server.trim_end_matches('/').to_string(),
+            client: builder.build()?,
+            token: token.map(Secret::new),
+            resources: Mutex::new(HashMap::new()),
+        })
+    }
+
+    fn kubeconfig_path() -> RopsResult<PathBuf> {
+        if let Some(path) = std::env::var("KUBECONFIG").ok().and_then(|paths| {
+            paths
+                .split(':')
+                .find(|path| !path.is_empty())
+                .map(PathBuf::from)
+        }) {
+            return Ok(path);
+        }
+        match std::env::home_dir() {
+            Some(home) => Ok(home.join(".kube").join("config")),
+            None => Err(RopsError::Error("Failed to get home directory".into())),
+        }
+    }
+
+    /// Run the credential plugin of the user and return its credentials
+    fn exec_credential(exec: &ExecConfig) -> RopsResult<ExecCredentialStatus> {
+        log::debug!("{} {}", exec.command, exec.args.join(" "));
+        let mut command = Command::new(&exec.command);
+        command.args(&exec.args);
+        for env in exec.env.iter().flatten() {
+            command.env(&env.name, &env.value);
+        }
+        let output = command.output().map_err(|err| {
+            RopsError::Error(format!(
+                "Failed to run kubeconfig credential plugin '{}': {err}",
+                exec.command
+            ))
+        })?;
+        if !output.status.success() {
+            return Err(RopsError::Error(format!(
+                "Kubeconfig credential plugin '{}' failed: {}",
+                exec.command,
+                String::from_utf8_lossy(&output.stderr).trim()
+            )));
+        }
+        let credential: Value = serde_json::from_slice(&output.stdout)?;
+        Ok(serde_json::from_value(credential["status"].clone())?)
+    }
+
+    fn request(&self, method: Method, path: &str) -> RequestBuilder {
+        let mut builder = self
+            .client
+            .request(method, format!("{}{path}", self.server))
+            .header("User-Agent", "quantmind/rops")
+            .header("Accept", "application/json");
+        if let Some(token) = self.token.as_ref() {
+            builder = builder.bearer_auth(token.value());
+        }
+        builder
+    }
+
+    fn send(&self, builder: RequestBuilder, path: &str) -> RopsResult<Option<Value>> {
+        let response = Self::execute(builder.timeout(REQUEST_TIMEOUT))?;
+        let status = response.status();
+        if status == StatusCode::NOT_FOUND {
+            return Ok(None);
+        }
+        let body: Value = response.json().unwrap_or(Value::Null);
+        if status.is_success() {
+            Ok(Some(body))
+        } else {
+            Err(RopsError::Error(format!(
+                "Kubernetes API {path} returned {status}: {}",
+                body["message"].as_str().unwrap_or_default()
+            )))
+        }
+    }
+
+    fn execute(builder: RequestBuilder) -> RopsResult<Response> {
+        builder.send().map_err(|err| {
+            // the connection and TLS failures are in the error sources
+            let mut message = err.to_string();
+            let mut source = std::error::Error::source(&err);
+            while let Some(err) = source {
+                message.push_str(&format!(": {err}"));
+                source = err.source();
+            }
+            RopsError::Error(message)
+        })
+    }
+
+    /// Get an object - `None` if it does not exist
+    pub fn get(&self, path: &str) -> RopsResult<Option<Value>> {
+        log::debug!("GET {path}");
+        self.send(self.request(Method::GET, path), path)
+    }
+
+    /// List the items of a collection matching a label selector
+    pub fn list(&self, path: &str, label_selector: Option<&str>) -> RopsResult<Vec<Value>> {
+        log::debug!("GET {path} {}", label_selector.unwrap_or_default());
+        let mut builder = self.request(Method::GET, path);
+        if let Some(selector) = label_selector {
+            builder = builder.query(&[("labelSelector", selector)]);
+        }
+        Ok(self
+            .send(builder, path)?
+            .and_then(|mut list| list["items"].as_array_mut().map(std::mem::take))
+            .unwrap_or_default())
+    }
+
+    /// Create an object in a collection
+    pub fn create(&self, path: &str, object: &Value) -> RopsResult<Value> {
+        log::debug!("POST {path}");
+        self.send(self.request(Method::POST, path).json(object), path)?
+            .ok_or_else(|| RopsError::Error(format!("Kubernetes API {path} not found")))
+    }
+
+    /// Delete an object and its dependents in the background, like kubectl - returns
+    /// false if it did not exist
+    pub fn delete(&self, path: &str) -> RopsResult<bool> {
+        log::debug!("DELETE {path}");
+        let builder = self
+            .request(Method::DELETE, path)
+            .query(&[("propagationPolicy", "Background")]);
+        Ok(self.send(builder, path)?.is_some())
+    }
+
+    /// Server-side apply an object to its path, returning the object as applied
+    ///
+    /// Conflicting fields of other managers are taken over, a dry run only validates
+    /// the object and returns how it would be applied.
+    pub fn apply(&self, path: &str, object: &Value, dry_run: bool) -> RopsResult<Value> {
+        log::debug!("PATCH {path} dry_run={dry_run}");
+        let mut builder = self
+            .request(Method::PATCH, path)
+            .query(&[("fieldManager", "rops"), ("force", "true")])
+            .header("Content-Type", "application/apply-patch+yaml")
+            // json is valid yaml
+            .body(object.to_string());
+        if dry_run {
+            builder = builder.query(&[("dryRun", "All")]);
+        }
+        self.send(builder, path)?
+            .ok_or_else(|| RopsError::Error(format!("Kubernetes API {path} not found")))
+    }
+
+    /// The resource of a kind in an api version, e.g. `apps/v1` and `Deployment`
+    pub fn resource(&self, api_version: &str, kind: &str) -> RopsResult<ApiResource> {
+        let mut resources = self
+            .resources
+            .lock()
+            .map_err(|_| RopsError::Error("Kubernetes discovery cache poisoned".into()))?;
+        if !resources.contains_key(api_version) {
+            let path = api_path(api_version);
+            let list = self.get(&path)?.ok_or_else(|| {
+                RopsError::Error(format!("API version '{api_version}' is not served"))
+            })?;
+            let served: Vec<ApiResource> = serde_json::from_value(list["resources"].clone())?;
+            resources.insert(
+                api_version.to_string(),
+                served
+                    .into_iter()
+                    // subresources such as deployments/status
+                    .filter(|resource| !resource.name.contains('/'))
+                    .collect(),
+            );
+        }
+        resources[api_version]
+            .iter()
+            .find(|resource| resource.kind == kind)
+            .cloned()
+            .ok_or_else(|| {
+                RopsError::Error(format!(
+                    "Kind '{kind}' is not served by API version '{api_version}'"
+                ))
+            })
+    }
+
+    /// Path of an object - namespaced objects without a namespace use the given one
+    pub fn object_path(&self, object: &Value, namespace: &str) -> RopsResult<String> {
+        let api_version = object["apiVersion"].as_str().unwrap_or_default();
+        let kind = object["kind"].as_str().unwrap_or_default();
+        let name = object["metadata"]["name"].as_str().unwrap_or_default();
+        if api_version.is_empty() || kind.is_empty() || name.is_empty() {
+            return Err(RopsError::Error(format!(
+                "Object without apiVersion, kind or name: {object}"
+            )));
+        }
+        let resource = self.resource(api_version, kind)?;
+        let prefix = api_path(api_version);
+        if resource.namespaced {
+            let namespace = object["metadata"]["namespace"]
+                .as_str()
+                .unwrap_or(namespace);
+            Ok(format!(
+                "{prefix}/namespaces/{namespace}/{}/{name}",
+                resource.name
+            ))
+        } else {
+            Ok(format!("{prefix}/{}/{name}", resource.name))
+        }
+    }
+
+    /// Stream the logs of a pod container - follows the logs until the pod terminates
+    pub fn logs(
+        &self,
+        namespace: &str,
+        pod: &str,
+        container: &str,
+        follow: bool,
+        tail: Option<u64>,
+    ) -> RopsResult<Response> {
+        let path = format!("/api/v1/namespaces/{namespace}/pods/{pod}/log");
+        log::debug!("GET {path} container={container} follow={follow}");
+        let mut builder = self
+            .request(Method::GET, &path)
+            .query(&[("container", container), ("follow", &follow.to_string())]);
+        if let Some(tail) = tail {
+            builder = builder.query(&[("tailLines", tail)]);
+        }
+        if !follow {
+            builder = builder.timeout(REQUEST_TIMEOUT);
+        }
+        let response = Self::execute(builder)?;
+        if response.status().is_success() {
+            Ok(response)
+        } else {
+            let status = response.status();
+            let body: Value = response.json().unwrap_or(Value::Null);
+            Err(RopsError::Error(format!(
+                "Failed to get logs of {pod}/{container}: {status} {}",
+                body["message"].as_str().unwrap_or_default()
+            )))
+        }
+    }
+
+    /// Deployments and statefulsets matching a label selector
+    pub fn workloads(&self, namespace: &str, label_selector: &str) -> RopsResult<Vec<Workload>> {
+        let mut workloads = vec![];
+        for (kind, resource) in [
+            ("Deployment", "deployments"),
+            ("StatefulSet", "statefulsets"),
+        ] {
+            let path = format!("/apis/apps/v1/namespaces/{namespace}/{resource}");
+            for item in self.list(&path, Some(label_selector))? {
+                workloads.push(Workload::from_value(kind, &item));
+            }
+        }
+        Ok(workloads)
+    }
+
+    /// Names of the services matching a label selector
+    pub fn services(&self, namespace: &str, label_selector: &str) -> RopsResult<Vec<String>> {
+        let path = format!("/api/v1/namespaces/{namespace}/services");
+        let mut services: Vec<String> = self
+            .list(&path, Some(label_selector))?
+            .iter()
+            .map(|item| as_string(&item["metadata"]["name"]))
+            .collect();
+        services.sort();
+        Ok(services)
+    }
+
+    /// Pods matching a label selector
+    pub fn pods(&self, namespace: &str, label_selector: &str) -> RopsResult<Vec<Pod>> {
+        let path = format!("/api/v1/namespaces/{namespace}/pods");
+        let mut pods: Vec<Pod> = self
+            .list(&path, Some(label_selector))?
+            .iter()
+            .map(Pod::from_value)
+            .collect();
+        pods.sort_by(|a, b| a.name.cmp(&b.name));
+        Ok(pods)
+    }
+
+    /// Events of a namespace, oldest first, optionally of the named objects only
+    pub fn events(&self, namespace: &str, names: Option<&[String]>) -> RopsResult<Vec<Event>> {
+        let path = format!("/api/v1/namespaces/{namespace}/events");
+        let mut events: Vec<Event> = self
+            .list(&path, None)?
+            .iter()
+            .map(Event::from_value)
+            .filter(|event| names.is_none_or(|names| names.contains(&event.name)))
+            .collect();
+        events.sort_by(|a, b| a.last_seen.cmp(&b.last_seen));
+        Ok(events)
+    }
+}
+
+/// Path of an api version - the core group is served under `/api`
+pub fn api_path(api_version: &str) -> String {
+    if api_version.contains('/') {
+        format!("/apis/{api_version}")
+    } else {
+        format!("/api/{api_version}")
+    }
+}
+
+/// Read base64 inline data or a file relative to the kubeconfig
+fn read_data(base: &Path, file: Option<&str>, data: Option<&str>) -> RopsResult<Option<Vec<u8>>> {
+    match (data, file) {
+        (Some(data), _) => general_purpose::STANDARD
+            .decode(data.trim())
+            .map(Some)
+            .map_err(|err| RopsError::Error(format!("Invalid base64 data in kubeconfig: {err}"))),
+        (None, Some(file)) => Ok(Some(std::fs::read(base.join(file))?)),
+        (None, None) => Ok(None),
+    }
+}
+
+fn as_u64(value: &Value) -> u64 {
+    value.as_u64().unwrap_or_default()
+}
+
+fn as_string(value: &Value) -> String {
+    value.as_str().unwrap_or_default().to_string()
+}
+
+impl Workload {
+    fn from_value(kind: &str, item: &Value) -> Self {
+        let metadata = &item["metadata"];
+        let status = &item["status"];
+        let replicas = item["spec"]["replicas"].as_u64().unwrap_or(1);
+        let ready = as_u64(&status["readyReplicas"]);
+        let updated = as_u64(&status["updatedReplicas"]);
+        let observed = as_u64(&status["observedGeneration"]) >= as_u64(&metadata["generation"]);
+        let rolled_out = observed
+            && updated >= replicas
+            && ready >= replicas
+            && match kind {
+                "Deployment" => {
+                    as_u64(&status["replicas"]) <= replicas
+                        && as_u64(&status["availableReplicas"]) >= replicas
+                }
+                _ => status["currentRevision"] == status["updateRevision"],
+            };
+        Self {
+            kind: kind.to_string(),
+            name: as_string(&metadata["name"]),
+            replicas,
+            ready,
+            updated,
+            rolled_out,
+        }
+    }
+}
+
+impl std::fmt::Display for Workload {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}/{}", self.kind.to_lowercase(), self.name)
+    }
+}
+
+impl Pod {
+    fn from_value(item: &Value) -> Self {
+        let statuses = item["status"]["containerStatuses"].as_array();
+        Self {
+            name: as_string(&item["metadata"]["name"]),
+            phase: as_string(&item["status"]["phase"]),
+            ready: item["status"]["conditions"]
+                .as_array()
+                .into_iter()
+                .flatten()
+                .any(|condition| condition["type"] == "Ready" && condition["status"] == "True"),
+            restarts: statuses
+                .into_iter()
+                .flatten()
+                .map(|status| as_u64(&status["restartCount"]))
+                .sum(),
+            containers: item["spec"]["containers"]
+                .as_array()
+                .into_iter()
+                .flatten()
+                .map(|container| as_string(&container["name"]))
+                .collect(),
+            created: as_string(&item["metadata"]["creationTimestamp"]),
+        }
+    }
+}
+
+impl Event {
+    fn from_value(item: &Value) -> Self {
+        let last_seen = [
+            &item["lastTimestamp"],
+            &item["eventTime"],
+            &item["metadata"]["creationTimestamp"],
+        ]
+        .into_iter()
+        .find_map(|timestamp| timestamp.as_str())
+        .unwrap_or_default()
+        .to_string();
+        Self {
+            kind: as_string(&item["involvedObject"]["kind"]),
+            name: as_string(&item["involvedObject"]["name"]),
+            event_type: as_string(&item["type"]),
+            reason: as_string(&item["reason"]),
+            message: as_string(&item["message"]),
+            count: item["count"].as_u64().unwrap_or(1),
+            last_seen,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{
+        io::{BufRead, BufReader, Read, Write},
+        net::TcpListener,
+        sync::mpsc,
+    };
+
+    /// A request received by the stub server
+    struct StubRequest {
+        /// method, path and query, e.g. `GET /api/v1/namespaces`
+        line: String,
+        headers: Vec<String>,
+        body: String,
+    }
+
+    /// Serve the responses in order, one per connection, and send back the requests
+    fn stub_server(responses: Vec<(u16, &'static str)>) -> (String, mpsc::Receiver<StubRequest>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+        let (sender, receiver) = mpsc::channel();
+        std::thread::spawn(move || {
+            for (status, body) in responses {
+                let (stream, _) = listener.accept().unwrap();
+                let mut reader = BufReader::new(stream.try_clone().unwrap());
+                let mut line = String::new();
+                reader.read_line(&mut line).unwrap();
+                let mut headers = vec![];
+                loop {
+                    let mut header = String::new();
+                    reader.read_line(&mut header).unwrap();
+                    if header.trim().is_empty() {
+                        break;
+                    }
+                    headers.push(header.trim().to_lowercase());
+                }
+                let length = headers
+                    .iter()
+                    .find_map(|header| header.strip_prefix("content-length: "))
+                    .map_or(0, |length| length.parse().unwrap());
+                let mut request_body = vec![0; length];
+                reader.read_exact(&mut request_body).unwrap();
+                let mut stream = stream;
+                write!(
+                    stream,
+                    "HTTP/1.1 {status} Stub\r\nContent-Type: application/json\r\n\
+                     Content-Length: {}\r\nConnection: close\r\n\r\n{body}",
+                    body.len()
+                )
+                .unwrap();
+                let line = line.trim().trim_end_matches(" HTTP/1.1").to_string();
+                sender
+                    .send(StubRequest {
+                        line,
+                        headers,
+                        body: String::from_utf8(request_body).unwrap(),
+                    })
+                    .unwrap();
+            }
+        });
+        (format!("http://{address}"), receiver)
+    }
+
+    fn client(server: &str) -> KubeClient {
+        let config = format!(
+            "current-context: test\nclusters:\n- name: test\n  cluster:\n    server: {server}\n\
+             contexts:\n- name: test\n  context:\n    cluster: test\n    user: test\n\
+             users:\n- name: test\n  user:\n    token: secret-token\n"
+        );
+        KubeClient::from_config(&config, Path::new("/tmp/kubeconfig")).unwrap()
+    }
+
+    #[test]
+    fn kubeconfig_current_context() {
+        let dir = tempfile::tempdir().unwrap();
+        std::fs::write(dir.path().join("token"), "file-token\n").unwrap();
+        let config = "current-context: prod\n\
+            clusters:\n\
+            - name: staging\n  cluster:\n    server: https://staging.example.com\n\
+            - name: prod\n  cluster:\n    server: https://prod.example.com:6443/\n\
+            contexts:\n\
+            - name: staging\n  context:\n    cluster: staging\n    user: staging\n\
+            - name: prod\n  context:\n    cluster: prod\n    user: prod\n\
+            users:\n\
+            - name: staging\n  user:\n    token: staging-token\n\
+            - name: prod\n  user:\n    tokenFile: token\n";
+        let client = KubeClient::from_config(config, &dir.path().join("config")).unwrap();
+        assert_eq!(client.server, "https://prod.example.com:6443");
+        assert_eq!(client.token.unwrap().value(), "file-token");
+
+        let error = KubeClient::from_config(
+            "current-context: dev\nclusters: []\ncontexts: []\n",
+            &dir.path().join("config"),
+        )
+        .err()
+        .unwrap();
+        assert!(error.to_string().contains("Context 'dev' not found"));
+    }
+
+    #[test]
+    fn read_inline_or_file_data() {
+        let dir = tempfile::tempdir().unwrap();
+        std::fs::write(dir.path().join("ca.crt"), "from file").unwrap();
+        assert_eq!(
+            read_data(dir.path(), Some("ca.crt"), Some(" aW5saW5l\n")).unwrap(),
+            Some(b"inline".to_vec())
+        );
+        assert_eq!(
+            read_data(dir.path(), Some("ca.crt"), None).unwrap(),
+            Some(b"from file".to_vec())
+        );
+        assert_eq!(read_data(dir.path(), None, None).unwrap(), None);
+        assert!(read_data(dir.path(), None, Some("not base64!")).is_err());
+    }
+
+    #[test]
+    fn workload_rollout() {
+        let deployment = serde_json::json!({
+            "metadata": {"name": "web", "generation": 3},
+            "spec": {"replicas": 2},
+            "status": {
+                "observedGeneration": 3,
+                "replicas": 2,
+                "readyReplicas": 2,
+                "updatedReplicas": 2,
+                "availableReplicas": 2,
+            },
+        });
+        let workload = Workload::from_value("Deployment", &deployment);
+        assert_eq!(workload.to_string(), "deployment/web");
+        assert_eq!((workload.replicas, workload.ready), (2, 2));
+        assert!(workload.rolled_out);
+
+        // old replicas still running
+        let mut rolling = deployment.clone();
+        rolling["status"]["replicas"] = 3.into();
+        assert!(!Workload::from_value("Deployment", &rolling).rolled_out);
+        // new spec not yet observed by the controller
+        let mut unobserved = deployment.clone();
+        unobserved["metadata"]["generation"] = 4.into();
+        assert!(!Workload::from_value("Deployment", &unobserved).rolled_out);
+
+        let statefulset = serde_json::json!({
+            "metadata": {"name": "db"},
+            "status": {
+                "readyReplicas": 1,
+                "updatedReplicas": 1,
+                "currentRevision": "db-1",
+                "updateRevision": "db-2",
+            },
+        });
+        let workload = Workload::from_value("StatefulSet", &statefulset);
+        assert_eq!(workload.replicas, 1);
+        assert!(!workload.rolled_out);
+    }
+
+    #[test]
+    fn client_requests() {
+        let (server, requests) = stub_server(vec![
+            (200, r#"{"metadata": {"name": "services"}}"#),
+            (404, r#"{"message": "not found"}"#),
+            (
+                200,
+                r#"{"items": [{"metadata": {"name": "a"}}, {"metadata": {"name": "b"}}]}"#,
+            ),
+            (403, r#"{"message": "forbidden"}"#),
+        ]);
+        let client = client(&server);
+
+        let namespace = client.get("/api/v1/namespaces/services").unwrap().unwrap();
+        assert_eq!(namespace["metadata"]["name"], "services");
+        let request = requests.recv().unwrap();
+        assert_eq!(request.line, "GET /api/v1/namespaces/services");
+        assert!(
+            request
+                .headers
+                .contains(&"authorization: bearer secret-token".to_string())
+        );
+
+        assert!(client.get("/api/v1/namespaces/missing").unwrap().is_none());
+        requests.recv().unwrap();
+
+        let items = client
+            .list("/api/v1/namespaces", Some("rops.io/preview=true"))
+            .unwrap();
+        assert_eq!(items.len(), 2);
+        assert_eq!(
+            requests.recv().unwrap().line,
+            "GET /api/v1/namespaces?labelSelector=rops.io%2Fpreview%3Dtrue"
+        );
+
+        let error = client.get("/api/v1/secrets").err().unwrap();
+        assert!(error.to_string().contains("403"));
+        assert!(error.to_string().contains("forbidden"));
+    }
+
+    #[test]
+    fn client_server_side_apply() {
+        let (server, requests) = stub_server(vec![
+            (
+                200,
+                r#"{"resources": [
+                    {"name": "deployments", "kind": "Deployment", "namespaced": true},
+                    {"name": "deployments/status", "kind": "Deployment", "namespaced": true}
+                ]}"#,
+            ),
+            (200, r#"{"kind": "Deployment"}"#),
+        ]);
+        let client = client(&server);
+        let object = serde_json::json!({
+            "apiVersion": "apps/v1",
+            "kind": "Deployment",
+            "metadata": {"name": "web"},
+        });
+        let path = client.object_path(&object, "services").unwrap();
+        assert_eq!(path, "/apis/apps/v1/namespaces/services/deployments/web");
+        assert_eq!(requests.recv().unwrap().line, "GET /apis/apps/v1");
+        // discovery is cached
+        client.resource("apps/v1", "Deployment").unwrap();
+        assert!(client.resource("apps/v1", "ReplicaSet").is_err());
+
+        client.apply(&path, &object, true).unwrap();
+        let request = requests.recv().unwrap();
+        assert_eq!(
+            request.line,
+            format!("PATCH {path}?fieldManager=rops&force=true&dryRun=All")
+        );
+        assert!(
+            request
+                .headers
+                .contains(&"content-type: application/apply-patch+yaml".to_string())
+        );
+        assert_eq!(
+            serde_json::from_str::<Value>(&request.body).unwrap(),
+            object
+        );
+    }
+
+    #[test]
+    fn api_paths() {
+        assert_eq!(api_path("v1"), "/api/v1");
+        assert_eq!(
+            api_path("networking.k8s.io/v1"),
+            "/apis/networking.k8s.io/v1"
+        );
+    }
+}
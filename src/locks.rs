@@ -0,0 +1,262 @@
+use crate::{
+    error::{RopsError, RopsResult},
+    kube::KubeClient,
+};
+use serde::{Deserialize, Serialize};
+use std::{cell::OnceCell, sync::OnceLock};
+
+const LOCK_LABEL: &str = "rops.io/lock";
+
+/// A deploy lock of a release, stored as a ConfigMap in the release namespace
+pub struct DeployLock {
+    pub name: String,
+    pub namespace: String,
+    client: OnceCell<KubeClient>,
+}
+
+/// Information about the holder of a deploy lock
+#[derive(Debug, Default, Clone, Deserialize, Serialize)]
+pub struct LockInfo {
+    pub owner: String,
+    pub chart: String,
+    pub env: String,
+    pub release: String,
+    pub sha: String,
+    pub branch: String,
+    pub timestamp: String,
+    /// set when the lock is held with `rops charts lock` rather than by a deploy
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub message: Option<String>,
+    /// identifies the rops process holding a deploy lock
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub token: Option<String>,
+}
+
+/// Outcome of acquiring a deploy lock
+#[derive(Debug, PartialEq)]
+pub enum Acquired {
+    /// the lock was created and should be released once done
+    Created,
+    /// the lock is already held by this process, or with `rops charts lock` by the same owner
+    Held,
+}
+
+impl std::fmt::Display for LockInfo {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{} since {} (chart {} env {} branch {} sha {})",
+            self.owner, self.timestamp, self.chart, self.env, self.branch, self.sha
+        )?;
+        if let Some(message) = self.message.as_ref() {
+            write!(f, " - {message}")?;
+        }
+        Ok(())
+    }
+}
+
+impl LockInfo {
+    /// Token of the current process - host, pid and a random part, as deploys of the
+    /// same owner can run concurrently from CI runners or terminals
+    pub fn process_token() -> String {
+        static TOKEN: OnceLock<String> = OnceLock::new();
+        TOKEN
+            .get_or_init(|| {
+                let host = std::fs::read_to_string("/etc/hostname")
+                    .ok()
+                    .or_else(|| std::env::var("HOSTNAME").ok())
+                    .map(|host| host.trim().to_string())
+                    .filter(|host| !host.is_empty())
+                    .unwrap_or_else(|| "localhost".to_string());
+                let random = crate::utils::random_base_64(6).unwrap_or_default();
+                format!("{host}-{}-{random}", std::process::id())
+            })
+            .clone()
+    }
+
+    /// Check if a lock can be re-entered - it is held by this process, or held with
+    /// `rops charts lock` by the same owner
+    pub fn reentrant(&self, info: &LockInfo) -> bool {
+        self.owner == info.owner
+            && (self.message.is_some() || (self.token.is_some() && self.token == info.token))
+    }
+}
+
+impl DeployLock {
+    pub fn new(release: &str, namespace: &str) -> Self {
+        Self {
+            name: format!("rops-lock-{release}"),
+            namespace: namespace.to_string(),
+            client: OnceCell::new(),
+        }
+    }
+
+    /// The Kubernetes client, created on first use
+    fn client(&self) -> RopsResult<&KubeClient> {
+        if let Some(client) = self.client.get() {
+            return Ok(client);
+        }
+        let client = KubeClient::from_kubeconfig()?;
+        Ok(self.client.get_or_init(|| client))
+    }
+
+    fn path(&self) -> String {
+        format!(
+            "/api/v1/namespaces/{}/configmaps/{}",
+            self.namespace, self.name
+        )
+    }
+
+    /// Create the namespace of the lock if it does not exist
+    ///
+    /// The namespace is applied server-side, so concurrent first deploys both succeed
+    /// and then compete for the lock inside it.
+    pub fn ensure_namespace(&self) -> RopsResult<()> {
+        let client = self.client()?;
+        let path = format!("/api/v1/namespaces/{}", self.namespace);
+        let exists = client
+            .get(&path)
+            .map_err(|err| {
+                RopsError::Error(format!(
+                    "Failed to get namespace '{}': {err}",
+                    self.namespace
+                ))
+            })?
+            .is_some();
+        if !exists {
+            log::info!("Creating namespace '{}'", self.namespace);
+            let namespace = serde_json::json!({
+                "apiVersion": "v1",
+                "kind": "Namespace",
+                "metadata": {"name": self.namespace},
+            });
+            client.apply(&path, &namespace, false).map_err(|err| {
+                RopsError::Error(format!(
+                    "Failed to create namespace '{}': {err}",
+                    self.namespace
+                ))
+            })?;
+        }
+        Ok(())
+    }
+
+    /// Get the current holder of the lock
+    pub fn get(&self) -> RopsResult<Option<LockInfo>> {
+        let config_map = self.client()?.get(&self.path()).map_err(|err| {
+            RopsError::Error(format!("Failed to get deploy lock '{}': {err}", self.name))
+        })?;
+        match config_map {
+            Some(config_map) => Ok(Some(serde_json::from_value(config_map["data"].clone())?)),
+            None => Ok(None),
+        }
+    }
+
+    /// Acquire the lock - fails if held by another owner unless forced
+    pub fn acquire(&self, info: &LockInfo, force: bool) -> RopsResult<Acquired> {
+        if let Some(existing) = self.get()? {
+            if existing.reentrant(info) && !force {
+                log::info!("Deploy lock '{}' already held by {existing}", self.name);
+                return Ok(Acquired::Held);
+            }
+            if !force {
+                return Err(RopsError::Error(format!(
+                    "Release '{}' in namespace '{}' is locked by {existing} - use --force to break the lock",
+                    info.release, self.namespace
+                )));
+            }
+            log::warn!("Breaking deploy lock '{}' held by {existing}", self.name);
+            self.release()?;
+        }
+        let config_map = serde_json::json!({
+            "apiVersion": "v1",
+            "kind": "ConfigMap",
+            "metadata": {
+                "name": self.name,
+                "namespace": self.namespace,
+                "labels": {
+                    LOCK_LABEL: "true",
+                    "app.kubernetes.io/managed-by": "rops",
+                },
+            },
+            "data": info,
+        });
+        let path = format!("/api/v1/namespaces/{}/configmaps", self.namespace);
+        match self.client()?.create(&path, &config_map) {
+            Ok(_) => {
+                log::info!("Deploy lock '{}' acquired by {}", self.name, info.owner);
+                Ok(Acquired::Created)
+            }
+            // another deploy created the lock in the meantime
+            Err(err) => Err(RopsError::Error(format!(
+                "Failed to acquire deploy lock '{}': {err}",
+                self.name
+            ))),
+        }
+    }
+
+    /// Release the lock
+    pub fn release(&self) -> RopsResult<()> {
+        self.client()?.delete(&self.path()).map_err(|err| {
+            RopsError::Error(format!(
+                "Failed to release deploy lock '{}': {err}",
+                self.name
+            ))
+        })?;
+        log::info!("Deploy lock '{}' released", self.name);
+        Ok(())
+    }
+
+    /// List deploy locks in a namespace or in all namespaces
+    pub fn list(namespace: Option<&str>) -> RopsResult<Vec<(String, LockInfo)>> {
+        let path = match namespace {
+            Some(namespace) => format!("/api/v1/namespaces/{namespace}/configmaps"),
+            None => "/api/v1/configmaps".to_string(),
+        };
+        let items = KubeClient::from_kubeconfig()?
+            .list(&path, Some(&format!("{LOCK_LABEL}=true")))
+            .map_err(|err| RopsError::Error(format!("Failed to list deploy locks: {err}")))?;
+        let mut locks = vec![];
+        for item in items.iter() {
+            let namespace = item["metadata"]["namespace"]
+                .as_str()
+                .unwrap_or_default()
+                .to_string();
+            locks.push((namespace, serde_json::from_value(item["data"].clone())?));
+        }
+        Ok(locks)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn info(owner: &str, token: Option<&str>, message: Option<&str>) -> LockInfo {
+        LockInfo {
+            owner: owner.to_string(),
+            token: token.map(str::to_string),
+            message: message.map(str::to_string),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn reentrant_locks() {
+        let deploy = info("ci", Some("runner-1-abc"), None);
+        // a concurrent deploy of the same owner
+        assert!(!deploy.reentrant(&info("ci", Some("runner-2-def"), None)));
+        assert!(deploy.reentrant(&info("ci", Some("runner-1-abc"), None)));
+        // locks without a token are only re-entered when held with `charts lock`
+        assert!(!info("ci", None, None).reentrant(&info("ci", None, None)));
+        let held = info("alice", None, Some("release freeze"));
+        assert!(held.reentrant(&info("alice", Some("host-1-abc"), None)));
+        assert!(!held.reentrant(&info("bob", Some("host-1-abc"), None)));
+    }
+
+    #[test]
+    fn process_token_is_stable() {
+        let token = LockInfo::process_token();
+        assert!(token.contains(&format!("-{}-", std::process::id())));
+        assert_eq!(token, LockInfo::process_token());
+    }
+}
@@ -1,11 +1,23 @@
 use clap::Parser;
+mod audit;
 mod blocks;
 mod charts;
 mod docker;
+mod drift;
 mod error;
 mod extra;
 mod git;
+mod health;
+mod helm_repos;
+mod kube;
+mod locks;
+mod manifests;
+mod notifications;
+mod preview;
+mod prompt;
 mod repo;
+mod scaffold;
+mod secrets;
 mod self_update;
 mod settings;
 mod system;
@@ -27,6 +39,9 @@ enum CliArgs {
     /// Manage repo and create a new git tag
     #[command(subcommand)]
     Repo(repo::RepoCommand),
+    /// Manage chart secrets with sops
+    #[command(subcommand)]
+    Secrets(secrets::SecretsCommand),
     /// Self update rops to latest version from github
     SelfUpdate,
     /// Third party tools management
@@ -39,9 +54,10 @@ enum CliArgs {
 
 fn main() {
     dotenv::from_path(".env").ok();
-    // Initialize logger with default info level if RUST_LOG is not set
+    // Initialize logger with default info level if RUST_LOG is not set - logs go to
+    // stderr so that stdout only carries command output, e.g. rendered manifests
     tracing_subscriber::registry()
-        .with(tracing_subscriber::fmt::layer())
+        .with(tracing_subscriber::fmt::layer().with_writer(std::io::stderr))
         .with(EnvFilter::try_from_default_env().unwrap_or_else(|_| EnvFilter::new("info")))
         .init();
     // run the application
@@ -68,6 +84,7 @@ fn run_app() -> error::RopsResult<()> {
         CliArgs::Docker(docker) => docker.run(&settings),
         CliArgs::Charts(charts) => charts.run(&settings),
         CliArgs::Repo(repo) => repo.run(&settings),
+        CliArgs::Secrets(secrets) => secrets.run(&settings),
         CliArgs::SelfUpdate => self_update::self_update(&settings),
         CliArgs::Tools(tools) => tools.run(&settings),
         CliArgs::Extra(extra) => extra.run(&settings),
@@ -0,0 +1,626 @@
+use crate::{
+    docker::image_reference,
+    drift::value_at,
+    error::{RopsError, RopsResult},
+    kube::{KubeClient, api_path},
+};
+use serde::{Deserialize, Serialize};
+use serde_yaml::Value;
+use std::{
+    collections::{BTreeMap, HashSet},
+    path::Path,
+    process::Command,
+};
+
+/// Label identifying the objects of a release deployed from manifests
+pub const RELEASE_LABEL: &str = "rops.io/release";
+
+/// Namespaced kinds pruned when they are no longer part of a release - api version,
+/// resource and kind
+const PRUNE_KINDS: &[(&str, &str, &str)] = &[
+    ("apps/v1", "deployments", "Deployment"),
+    ("apps/v1", "statefulsets", "StatefulSet"),
+    ("apps/v1", "daemonsets", "DaemonSet"),
+    ("v1", "services", "Service"),
+    ("v1", "configmaps", "ConfigMap"),
+    ("v1", "secrets", "Secret"),
+    ("v1", "serviceaccounts", "ServiceAccount"),
+    ("v1", "persistentvolumeclaims", "PersistentVolumeClaim"),
+    ("networking.k8s.io/v1", "ingresses", "Ingress"),
+    ("batch/v1", "cronjobs", "CronJob"),
+    ("batch/v1", "jobs", "Job"),
+    (
+        "autoscaling/v2",
+        "horizontalpodautoscalers",
+        "HorizontalPodAutoscaler",
+    ),
+    ("policy/v1", "poddisruptionbudgets", "PodDisruptionBudget"),
+    ("rbac.authorization.k8s.io/v1", "roles", "Role"),
+    (
+        "rbac.authorization.k8s.io/v1",
+        "rolebindings",
+        "RoleBinding",
+    ),
+];
+
+/// How a chart is rendered and deployed
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ChartType {
+    /// a helm chart installed with `helm upgrade --install`
+    #[default]
+    Helm,
+    /// a kustomize overlay built with `kubectl kustomize`
+    Kustomize,
+    /// a directory of plain yaml manifests, or a single file
+    Manifests,
+}
+
+/// Kubernetes objects rendered from a kustomize overlay or manifests
+pub struct Manifests {
+    pub documents: Vec<Value>,
+}
+
+impl Manifests {
+    /// Render the objects of a kustomize overlay or manifest directory
+    pub fn render(chart_type: ChartType, path: &str) -> RopsResult<Self> {
+        let content = match chart_type {
+            ChartType::Helm => {
+                return Err(RopsError::Error(format!(
+                    "'{path}' is a helm chart, not manifests"
+                )));
+            }
+            ChartType::Kustomize => {
+                log::info!("kubectl kustomize {path}");
+                let output = Command::new("kubectl")
+                    .arg("kustomize")
+                    .arg(path)
+                    .output()?;
+                if !output.status.success() {
+                    return Err(RopsError::Error(format!(
+                        "Failed to build kustomize overlay '{path}': {}",
+                        String::from_utf8_lossy(&output.stderr).trim()
+                    )));
+                }
+                String::from_utf8_lossy(&output.stdout).to_string()
+            }
+            ChartType::Manifests => {
+                let path = Path::new(path);
+                let mut files = vec![];
+                if path.is_dir() {
+                    for entry in std::fs::read_dir(path)? {
+                        let file = entry?.path();
+                        if file
+                            .extension()
+                            .is_some_and(|extension| extension == "yaml" || extension == "yml")
+                        {
+                            files.push(file);
+                        }
+                    }
+                    files.sort();
+                } else if path.is_file() {
+                    files.push(path.to_path_buf());
+                } else {
+                    return Err(RopsError::Error(format!(
+                        "Manifests '{}' not found",
+                        path.display()
+                    )));
+                }
+                let mut content = String::new();
+                for file in files {
+                    log::info!("Manifests: {}", file.display());
+                    content.push_str(&std::fs::read_to_string(&file)?);
+                    content.push_str("\n---\n");
+                }
+                content
+            }
+        };
+        let mut documents = vec![];
+        for document in serde_yaml::Deserializer::from_str(&content) {
+            let value = Value::deserialize(document)?;
+            if value.is_mapping() {
+                documents.push(value);
+            }
+        }
+        if documents.is_empty() {
+            return Err(RopsError::Error(format!("No manifests found in '{path}'")));
+        }
+        Ok(Self { documents })
+    }
+
+    /// Label all objects and their existing pod templates with the release label
+    pub fn label(&mut self, release: &str) {
+        for document in self.documents.iter_mut() {
+            set_label(document, release);
+            let Some(spec) = document.get_mut("spec") else {
+                continue;
+            };
+            if let Some(template) = spec.get_mut("template") {
+                set_label(template, release);
+            }
+            if let Some(template) = spec
+                .get_mut("jobTemplate")
+                .and_then(|job| job.get_mut("spec"))
+                .and_then(|spec| spec.get_mut("template"))
+            {
+                set_label(template, release);
+            }
+        }
+    }
+
+    /// Replace `${path}` placeholders in string values with the merged vars
+    ///
+    /// `$${` escapes a literal `${`, undefined placeholders are an error.
+    pub fn substitute(&mut self, vars: &Value) -> RopsResult<()> {
+        let vars = serde_json::to_value(vars)?;
+        let mut missing = vec![];
+        for document in self.documents.iter_mut() {
+            substitute(document, &vars, &mut missing);
+        }
+        if missing.is_empty() {
+            Ok(())
+        } else {
+            missing.sort();
+            missing.dedup();
+            Err(RopsError::Error(format!(
+                "Undefined vars in manifests: {}",
+                missing.join(", ")
+            )))
+        }
+    }
+
+    /// Set the tag of container images from the given repositories
+    pub fn set_images(&mut self, repositories: &BTreeMap<String, String>, tag: &str) {
+        for document in self.documents.iter_mut() {
+            set_images(document, repositories, tag);
+        }
+    }
+
+    pub fn to_yaml(&self) -> RopsResult<String> {
+        let mut yaml = String::new();
+        for document in self.documents.iter() {
+            yaml.push_str("---\n");
+            yaml.push_str(&serde_yaml::to_string(document)?);
+        }
+        Ok(yaml)
+    }
+
+    /// Server-side apply the objects and prune objects of the release no longer rendered
+    ///
+    /// A dry run validates the objects with the API server without persisting them.
+    pub fn apply(
+        &self,
+        client: &KubeClient,
+        release: &str,
+        namespace: &str,
+        dry_run: bool,
+    ) -> RopsResult<()> {
+        for object in self.objects(client, namespace)? {
+            let (path, object) = object?;
+            log::info!(
+                "Applying {}/{}{}",
+                object["kind"].as_str().unwrap_or_default(),
+                object["metadata"]["name"].as_str().unwrap_or_default(),
+                if dry_run { " (dry run)" } else { "" }
+            );
+            client.apply(&path, &object, dry_run).map_err(|err| {
+                RopsError::Error(format!(
+                    "Failed to apply manifests of release '{release}': {err}"
+                ))
+            })?;
+        }
+        Self::prune(client, release, namespace, Some(self), dry_run)
+    }
+
+    /// Delete the objects of a release in the namespace which are not kept
+    pub fn prune(
+        client: &KubeClient,
+        release: &str,
+        namespace: &str,
+        keep: Option<&Manifests>,
+        dry_run: bool,
+    ) -> RopsResult<()> {
+        let selector = format!("{RELEASE_LABEL}={release}");
+        let keep: HashSet<(String, String)> = keep
+            .map(|manifests| {
+                manifests
+                    .documents
+                    .iter()
+                    .map(|document| {
+                        (
+                            document["kind"].as_str().unwrap_or_default().to_string(),
+                            document["metadata"]["name"]
+                                .as_str()
+                                .unwrap_or_default()
+                                .to_string(),
+                        )
+                    })
+                    .collect()
+            })
+            .unwrap_or_default();
+        for (api_version, resource, kind) in PRUNE_KINDS {
+            let collection = format!(
+                "{}/namespaces/{namespace}/{resource}",
+                api_path(api_version)
+            );
+            // items of a list have no kind, kinds not served by the cluster are empty
+            for item in client.list(&collection, Some(&selector))? {
+                let name = item["metadata"]["name"].as_str().unwrap_or_default();
+                // objects created by controllers, e.g. jobs of cronjobs, are removed with their owner
+                if item["metadata"]["ownerReferences"].is_array()
+                    || keep.contains(&(kind.to_string(), name.to_string()))
+                {
+                    continue;
+                }
+                log::info!("Deleting {kind}/{name} of release '{release}'");
+                if dry_run {
+                    continue;
+                }
+                client
+                    .delete(&format!("{collection}/{name}"))
+                    .map_err(|err| {
+                        RopsError::Error(format!(
+                            "Failed to delete {kind}/{name} of release '{release}': {err}"
+                        ))
+                    })?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Objects which differ from the cluster, comparing the live objects with a dry run
+    /// of the server-side apply
+    pub fn diff(&self, client: &KubeClient, namespace: &str) -> RopsResult<Vec<String>> {
+        let mut changes = vec![];
+        for object in self.objects(client, namespace)? {
+            let (path, object) = object?;
+            let name = format!(
+                "{}/{}",
+                object["kind"].as_str().unwrap_or_default(),
+                object["metadata"]["name"].as_str().unwrap_or_default()
+            );
+            match client.get(&path)? {
+                None => changes.push(format!("{name} added")),
+                Some(live) => {
+                    let applied = client.apply(&path, &object, true)?;
+                    if comparable(live) != comparable(applied) {
+                        changes.push(format!("{name} changed"));
+                    }
+                }
+            }
+        }
+        Ok(changes)
+    }
+
+    /// API paths and json of the objects
+    fn objects<'a>(
+        &'a self,
+        client: &'a KubeClient,
+        namespace: &'a str,
+    ) -> RopsResult<impl Iterator<Item = RopsResult<(String, serde_json::Value)>> + 'a> {
+        Ok(self.documents.iter().map(move |document| {
+            let object = serde_json::to_value(document)?;
+            Ok((client.object_path(&object, namespace)?, object))
+        }))
+    }
+}
+
+/// An object without the fields the API server changes on every write
+fn comparable(mut object: serde_json::Value) -> serde_json::Value {
+    if let Some(metadata) = object["metadata"].as_object_mut() {
+        for field in ["managedFields", "resourceVersion", "generation"] {
+            metadata.remove(field);
+        }
+    }
+    if let Some(object) = object.as_object_mut() {
+        object.remove("status");
+    }
+    object
+}
+
+/// Add the release label to an object or pod template
+///
+/// Only existing mappings are changed - a missing metadata or labels mapping is added
+/// to them, other fields are never inserted.
+fn set_label(object: &mut Value, release: &str) {
+    let Value::Mapping(object) = object else {
+        return;
+    };
+    let metadata = object
+        .entry("metadata".into())
+        .or_insert_with(|| Value::Mapping(Default::default()));
+    if metadata.is_null() {
+        *metadata = Value::Mapping(Default::default());
+    }
+    let Value::Mapping(metadata) = metadata else {
+        return;
+    };
+    let labels = metadata
+        .entry("labels".into())
+        .or_insert_with(|| Value::Mapping(Default::default()));
+    if labels.is_null() {
+        *labels = Value::Mapping(Default::default());
+    }
+    if let Value::Mapping(labels) = labels {
+        labels.insert(RELEASE_LABEL.into(), release.into());
+    }
+}
+
+fn substitute(value: &mut Value, vars: &serde_json::Value, missing: &mut Vec<String>) {
+    match value {
+        Value::String(text) if text.contains("${") => {
+            let mut result = String::new();
+            let mut rest = text.as_str();
+            while let Some(start) = rest.find("${") {
+                // `$${` is an escaped `${`
+                if rest[..start].ends_with('$') {
+                    result.push_str(&rest[..start - 1]);
+                    result.push_str("${");
+                    rest = &rest[start + 2..];
+                    continue;
+                }
+                let Some(end) = rest[start..].find('}') else {
+                    break;
+                };
+                result.push_str(&rest[..start]);
+                let path = &rest[start + 2..start + end];
+                match value_at(vars, path) {
+                    Some(serde_json::Value::String(var)) => result.push_str(var),
+                    Some(var @ (serde_json::Value::Number(_) | serde_json::Value::Bool(_))) => {
+                        result.push_str(&var.to_string())
+                    }
+                    _ => missing.push(path.to_string()),
+                }
+                rest = &rest[start + end + 1..];
+            }
+            result.push_str(rest);
+            *text = result;
+        }
+        Value::Mapping(mapping) => {
+            for (_, value) in mapping.iter_mut() {
+                substitute(value, vars, missing);
+            }
+        }
+        Value::Sequence(sequence) => {
+            for value in sequence.iter_mut() {
+                substitute(value, vars, missing);
+            }
+        }
+        _ => {}
+    }
+}
+
+/// Check if an image repository refers to a repository url - images can omit the
+/// registry of the url and docker hub repositories their `docker.io/library/` prefix
+fn same_repository(url: &str, repository: &str) -> bool {
+    let normalize = |repository: &str| {
+        let repository = repository.strip_prefix("docker.io/").unwrap_or(repository);
+        repository
+            .strip_prefix("library/")
+            .unwrap_or(repository)
+            .to_string()
+    };
+    let (url, repository) = (normalize(url), normalize(repository));
+    url == repository || url.ends_with(&format!("/{repository}"))
+}
+
+fn set_images(value: &mut Value, repositories: &BTreeMap<String, String>, tag: &str) {
+    match value {
+        Value::Mapping(mapping) => {
+            for (key, value) in mapping.iter_mut() {
+                if key.as_str() == Some("image")
+                    && let Some(image) = value.as_str()
+                {
+                    let repository = image.split('@').next().unwrap_or(image);
+                    let repository = match repository.rsplit_once(':') {
+                        // a colon after the last slash separates the tag
+                        Some((name, tag)) if !tag.contains('/') => name,
+                        _ => repository,
+                    };
+                    if let Some(url) = repositories
+                        .values()
+                        .find(|url| same_repository(url, repository))
+                    {
+                        *value = Value::from(image_reference(url, tag));
+                    }
+                } else {
+                    set_images(value, repositories, tag);
+                }
+            }
+        }
+        Value::Sequence(sequence) => {
+            for value in sequence.iter_mut() {
+                set_images(value, repositories, tag);
+            }
+        }
+        _ => {}
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn manifests(yaml: &str) -> Manifests {
+        Manifests {
+            documents: serde_yaml::Deserializer::from_str(yaml)
+                .map(|document| Value::deserialize(document).unwrap())
+                .collect(),
+        }
+    }
+
+    #[test]
+    fn label_only_changes_existing_fields() {
+        let mut manifests = manifests(
+            "apiVersion: v1
+kind: ConfigMap
+metadata:
+  name: config
+data:
+  key: value
+---
+apiVersion: apps/v1
+kind: Deployment
+metadata:
+  name: web
+spec:
+  template:
+    metadata:
+      labels:
+        app: web
+    spec:
+      containers:
+      - name: web
+        image: nginx
+",
+        );
+        manifests.label("web-services");
+        assert_eq!(
+            manifests.to_yaml().unwrap(),
+            "---
+apiVersion: v1
+kind: ConfigMap
+metadata:
+  name: config
+  labels:
+    rops.io/release: web-services
+data:
+  key: value
+---
+apiVersion: apps/v1
+kind: Deployment
+metadata:
+  name: web
+  labels:
+    rops.io/release: web-services
+spec:
+  template:
+    metadata:
+      labels:
+        app: web
+        rops.io/release: web-services
+    spec:
+      containers:
+      - name: web
+        image: nginx
+"
+        );
+    }
+
+    #[test]
+    fn label_cronjob_template() {
+        let mut manifests = manifests(
+            "kind: CronJob
+metadata:
+  name: job
+spec:
+  jobTemplate:
+    spec:
+      template:
+        spec: {}
+",
+        );
+        manifests.label("job");
+        let document = &manifests.documents[0];
+        assert_eq!(
+            document["spec"]["jobTemplate"]["spec"]["template"]["metadata"]["labels"]
+                [RELEASE_LABEL],
+            Value::from("job")
+        );
+        assert!(document["spec"].get("template").is_none());
+    }
+
+    #[test]
+    fn substitute_vars() {
+        let mut manifests = manifests(
+            "kind: ConfigMap
+data:
+  host: ${db.host}:${db.port}
+  debug: '${debug}'
+  script: echo $${HOME}
+",
+        );
+        let vars = serde_yaml::from_str("db: {host: db.local, port: 5432}\ndebug: true").unwrap();
+        manifests.substitute(&vars).unwrap();
+        let data = &manifests.documents[0]["data"];
+        assert_eq!(data["host"], Value::from("db.local:5432"));
+        assert_eq!(data["debug"], Value::from("true"));
+        assert_eq!(data["script"], Value::from("echo ${HOME}"));
+    }
+
+    #[test]
+    fn substitute_undefined_vars() {
+        let mut manifests = manifests("kind: ConfigMap\ndata:\n  a: ${missing}\n  b: ${db}\n");
+        let vars = serde_yaml::from_str("db: {host: db.local}").unwrap();
+        let err = manifests.substitute(&vars).unwrap_err().to_string();
+        assert!(err.contains("db, missing"), "{err}");
+    }
+
+    #[test]
+    fn set_images_of_repositories() {
+        let mut manifests = manifests(
+            "kind: Deployment
+spec:
+  template:
+    spec:
+      containers:
+      - image: reg.io/org/web:1.0
+      - image: web@sha256:abc
+      - image: nginx:1.25
+      - image: reg.io/org/web-worker:1.0
+",
+        );
+        let repositories = BTreeMap::from([("web".to_string(), "reg.io/org/web".to_string())]);
+        manifests.set_images(&repositories, "2.0");
+        let containers = &manifests.documents[0]["spec"]["template"]["spec"]["containers"];
+        assert_eq!(containers[0]["image"], Value::from("reg.io/org/web:2.0"));
+        assert_eq!(containers[1]["image"], Value::from("reg.io/org/web:2.0"));
+        assert_eq!(containers[2]["image"], Value::from("nginx:1.25"));
+        assert_eq!(
+            containers[3]["image"],
+            Value::from("reg.io/org/web-worker:1.0")
+        );
+    }
+
+    #[test]
+    fn comparable_objects() {
+        let live = serde_json::json!({
+            "kind": "ConfigMap",
+            "metadata": {"name": "web", "resourceVersion": "1", "managedFields": []},
+            "data": {"a": "1"},
+        });
+        let applied = serde_json::json!({
+            "kind": "ConfigMap",
+            "metadata": {"name": "web", "resourceVersion": "2", "generation": 2},
+            "data": {"a": "1"},
+            "status": {},
+        });
+        assert_eq!(comparable(live.clone()), comparable(applied));
+        let changed = serde_json::json!({
+            "kind": "ConfigMap",
+            "metadata": {"name": "web"},
+            "data": {"a": "2"},
+        });
+        assert_ne!(comparable(live), comparable(changed));
+    }
+
+    #[test]
+    fn set_image_digests() {
+        let mut manifests = manifests(
+            "kind: Pod\nspec:\n  containers:\n    - name: web\n      image: reg.io/web:old\n",
+        );
+        let repositories = BTreeMap::from([("image".to_string(), "reg.io/web".to_string())]);
+        manifests.set_images(&repositories, "@sha256:4f1c");
+        assert_eq!(
+            manifests.documents[0]["spec"]["containers"][0]["image"],
+            Value::from("reg.io/web@sha256:4f1c")
+        );
+    }
+
+    #[test]
+    fn same_docker_hub_repository() {
+        assert!(same_repository("docker.io/library/nginx", "nginx"));
+        assert!(same_repository("nginx", "docker.io/nginx"));
+        assert!(!same_repository("reg.io/org/web", "other/web"));
+    }
+}
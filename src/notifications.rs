@@ -0,0 +1,257 @@
+use crate::error::{RopsError, RopsResult};
+use reqwest::blocking::Client;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::time::Duration;
+
+#[derive(Debug, Default, Clone, Deserialize, Serialize)]
+pub struct NotificationSettings {
+    #[serde(default)]
+    pub webhooks: Vec<Webhook>,
+}
+
+/// A Slack compatible webhook - messages are posted as `{"text": "..."}`
+#[derive(Debug, Default, Clone, Deserialize, Serialize)]
+pub struct Webhook {
+    /// webhook url - a value starting with `$` is read from that environment variable
+    pub url: String,
+    /// events to notify, `deploy` and `push` - all events if empty
+    #[serde(default)]
+    pub events: Vec<String>,
+    /// message template for successful events
+    pub template: Option<String>,
+    /// message template for failed events
+    pub failure_template: Option<String>,
+}
+
+/// A deploy or push event - fields are available in templates as `{field}`
+pub struct Notification {
+    pub event: &'static str,
+    pub success: bool,
+    pub fields: BTreeMap<&'static str, String>,
+}
+
+impl NotificationSettings {
+    /// Post the notification to the webhooks subscribed to its event
+    ///
+    /// Failures are logged rather than returned so that notifications never fail a command
+    pub fn notify(&self, notification: &Notification) {
+        for webhook in self
+            .webhooks
+            .iter()
+            .filter(|webhook| webhook.accepts(notification.event))
+        {
+            if let Err(err) = webhook.post(notification) {
+                log::error!("Failed to send {} notification: {err}", notification.event);
+            }
+        }
+    }
+}
+
+impl Webhook {
+    fn accepts(&self, event: &str) -> bool {
+        self.events.is_empty() || self.events.iter().any(|e| e == event)
+    }
+
+    fn url(&self) -> RopsResult<String> {
+        match self.url.strip_prefix('$') {
+            Some(var) => std::env::var(var).map_err(|_| {
+                RopsError::Error(format!("Webhook url environment variable '{var}' not set"))
+            }),
+            None => Ok(self.url.clone()),
+        }
+    }
+
+    fn post(&self, notification: &Notification) -> RopsResult<()> {
+        let template = if notification.success {
+            self.template.clone()
+        } else {
+            self.failure_template.clone()
+        }
+        .unwrap_or_else(|| notification.default_template());
+        let text = notification.render(&template);
+        let response = Client::builder()
+            .timeout(Duration::from_secs(10))
+            .build()?
+            .post(self.url()?)
+            .header("User-Agent", "quantmind/rops")
+            .json(&serde_json::json!({ "text": text }))
+            .send()?;
+        if response.status().is_success() {
+            Ok(())
+        } else {
+            Err(RopsError::Error(format!(
+                "status {}: {}",
+                response.status(),
+                response.text()?
+            )))
+        }
+    }
+}
+
+impl Notification {
+    pub fn new(event: &'static str) -> Self {
+        Self {
+            event,
+            success: true,
+            fields: BTreeMap::new(),
+        }
+    }
+
+    pub fn with_field<S: Into<String>>(mut self, name: &'static str, value: S) -> Self {
+        self.fields.insert(name, value.into());
+        self
+    }
+
+    /// Set the outcome of the event from a command result
+    pub fn with_result<T>(mut self, result: &RopsResult<T>, duration: Duration) -> Self {
+        self.success = result.is_ok();
+        if let Err(err) = result {
+            self.fields.insert("error", err.to_string());
+        }
+        self.fields
+            .insert("duration", format!("{:.1}s", duration.as_secs_f64()));
+        self
+    }
+
+    fn default_template(&self) -> String {
+        match (self.event, self.success) {
+            ("deploy", true) => {
+                "Deployed *{chart}* to *{env}* ({cluster}/{namespace}) with tag `{tag}` in {duration}"
+            }
+            ("deploy", false) => {
+                "Failed to deploy *{chart}* to *{env}* ({cluster}/{namespace}) with tag `{tag}` after {duration}: {error}"
+            }
+            (_, true) => "Pushed `{image}` in {duration}",
+            (_, false) => "Failed to push `{image}` after {duration}: {error}",
+        }
+        .to_string()
+    }
+
+    fn render(&self, template: &str) -> String {
+        self.fields
+            .iter()
+            .fold(template.to_string(), |text, (name, value)| {
+                text.replace(&format!("{{{name}}}"), value)
+            })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{
+        io::{BufRead, BufReader, Read, Write},
+        net::TcpListener,
+        sync::mpsc,
+    };
+
+    /// Reply to one request with the status and send back the json body posted
+    fn stub_server(status: u16) -> (String, mpsc::Receiver<serde_json::Value>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+        let (sender, receiver) = mpsc::channel();
+        std::thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let mut reader = BufReader::new(stream.try_clone().unwrap());
+            let mut length = 0;
+            loop {
+                let mut line = String::new();
+                reader.read_line(&mut line).unwrap();
+                let line = line.trim().to_lowercase();
+                if line.is_empty() {
+                    break;
+                }
+                if let Some(value) = line.strip_prefix("content-length: ") {
+                    length = value.parse().unwrap();
+                }
+            }
+            let mut body = vec![0; length];
+            reader.read_exact(&mut body).unwrap();
+            write!(
+                stream,
+                "HTTP/1.1 {status} Stub\r\nContent-Length: 2\r\nConnection: close\r\n\r\nok"
+            )
+            .unwrap();
+            sender.send(serde_json::from_slice(&body).unwrap()).unwrap();
+        });
+        (format!("http://{address}/hook"), receiver)
+    }
+
+    fn deploy(success: bool) -> Notification {
+        let result: RopsResult<()> = if success {
+            Ok(())
+        } else {
+            Err(RopsError::Error("timed out".into()))
+        };
+        Notification::new("deploy")
+            .with_field("chart", "web")
+            .with_field("env", "prod")
+            .with_field("cluster", "main")
+            .with_field("namespace", "services")
+            .with_field("tag", "v1.2.0")
+            .with_result(&result, Duration::from_millis(12300))
+    }
+
+    #[test]
+    fn slack_payload_of_default_templates() {
+        let (url, payloads) = stub_server(200);
+        let webhook = Webhook {
+            url,
+            ..Default::default()
+        };
+        webhook.post(&deploy(true)).unwrap();
+        assert_eq!(
+            payloads.recv().unwrap(),
+            serde_json::json!({
+                "text": "Deployed *web* to *prod* (main/services) with tag `v1.2.0` in 12.3s"
+            })
+        );
+
+        let (url, payloads) = stub_server(200);
+        let webhook = Webhook {
+            url,
+            ..Default::default()
+        };
+        webhook.post(&deploy(false)).unwrap();
+        assert_eq!(
+            payloads.recv().unwrap()["text"],
+            "Failed to deploy *web* to *prod* (main/services) with tag `v1.2.0` after 12.3s: \
+             timed out"
+        );
+    }
+
+    #[test]
+    fn webhook_payload_of_templates() {
+        let (url, payloads) = stub_server(200);
+        // SAFETY: the variable is only read by this test
+        unsafe { std::env::set_var("ROPS_TEST_WEBHOOK_URL", &url) };
+        let webhook = Webhook {
+            url: "$ROPS_TEST_WEBHOOK_URL".into(),
+            events: vec!["push".into()],
+            template: Some("{image} pushed from {branch}".into()),
+            failure_template: None,
+        };
+        let push = Notification::new("push")
+            .with_field("image", "reg.io/web:v1-arm64")
+            .with_field("branch", "main");
+        assert!(webhook.accepts("push"));
+        assert!(!webhook.accepts("deploy"));
+        webhook.post(&push).unwrap();
+        assert_eq!(
+            payloads.recv().unwrap(),
+            serde_json::json!({"text": "reg.io/web:v1-arm64 pushed from main"})
+        );
+    }
+
+    #[test]
+    fn webhook_error_status() {
+        let (url, _payloads) = stub_server(500);
+        let webhook = Webhook {
+            url,
+            ..Default::default()
+        };
+        let error = webhook.post(&deploy(true)).err().unwrap();
+        assert!(error.to_string().contains("500"));
+    }
+}
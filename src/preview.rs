@@ -0,0 +1,226 @@
+use crate::{error::RopsResult, kube::KubeClient, utils::hashed_dns_label};
+use reqwest::Url;
+use std::process::Command;
+
+const PREVIEW_LABEL: &str = "rops.io/preview";
+const BRANCH_ANNOTATION: &str = "rops.io/branch";
+const CREATED_ANNOTATION: &str = "rops.io/created";
+const BLOCKS_ANNOTATION: &str = "rops.io/blocks";
+
+/// The namespace of a preview environment of a branch
+pub struct PreviewNamespace {
+    pub name: String,
+    pub branch: String,
+    /// unix timestamp of the creation of the namespace
+    pub created: u64,
+    /// blocks created for the preview as `space/name`
+    pub blocks: Vec<String>,
+}
+
+impl PreviewNamespace {
+    pub fn new(branch: &str) -> Self {
+        Self {
+            name: Self::namespace_name(branch),
+            branch: branch.to_string(),
+            created: now_seconds(),
+            blocks: vec![],
+        }
+    }
+
+    /// Namespace name derived from the branch - a valid DNS-1123 label with a hash of
+    /// the branch, unique even when long branch names are truncated
+    pub fn namespace_name(branch: &str) -> String {
+        format!("preview-{}", hashed_dns_label(branch, 55))
+    }
+
+    /// Subdomain label of the branch used to name preview blocks
+    pub fn subdomain(&self) -> String {
+        hashed_dns_label(&self.branch, 40)
+    }
+
+    /// Upstream of a preview block - the namespace of a service host is replaced with
+    /// the preview namespace, e.g. `web.services.svc` becomes `web.preview-x.svc`
+    pub fn upstream(&self, upstream: &str, namespace: &str) -> String {
+        let Some(host) = Url::parse(upstream)
+            .ok()
+            .and_then(|url| url.host_str().map(str::to_string))
+        else {
+            return upstream.to_string();
+        };
+        let mut labels: Vec<&str> = host.split('.').collect();
+        if labels.get(1) != Some(&namespace) {
+            return upstream.to_string();
+        }
+        labels[1] = &self.name;
+        upstream.replacen(
+            &format!("://{host}"),
+            &format!("://{}", labels.join(".")),
+            1,
+        )
+    }
+
+    /// Age of the preview in days
+    pub fn age_days(&self) -> u64 {
+        now_seconds().saturating_sub(self.created) / 86400
+    }
+
+    /// Create or update the namespace, preserving its creation time and blocks
+    pub fn apply(&mut self, client: &KubeClient, dry_run: bool) -> RopsResult<()> {
+        log::info!("Applying namespace {}", self.name);
+        if dry_run {
+            log::info!("Dry run mode enabled, skipping actual command execution.");
+            return Ok(());
+        }
+        if let Some(existing) = Self::get(client, &self.name)? {
+            self.created = existing.created;
+            for block in existing.blocks {
+                if !self.blocks.contains(&block) {
+                    self.blocks.push(block);
+                }
+            }
+        }
+        let namespace = serde_json::json!({
+            "apiVersion": "v1",
+            "kind": "Namespace",
+            "metadata": {
+                "name": self.name,
+                "labels": {
+                    PREVIEW_LABEL: "true",
+                    "app.kubernetes.io/managed-by": "rops",
+                },
+                "annotations": {
+                    BRANCH_ANNOTATION: self.branch,
+                    CREATED_ANNOTATION: self.created.to_string(),
+                    BLOCKS_ANNOTATION: self.blocks.join(","),
+                },
+            },
+        });
+        client.apply(
+            &format!("/api/v1/namespaces/{}", self.name),
+            &namespace,
+            false,
+        )?;
+        Ok(())
+    }
+
+    /// Delete the namespace and everything deployed in it
+    pub fn delete(&self, client: &KubeClient, dry_run: bool) -> RopsResult<()> {
+        log::info!("Deleting namespace {}", self.name);
+        if dry_run {
+            log::info!("Dry run mode enabled, skipping actual command execution.");
+            return Ok(());
+        }
+        if !client.delete(&format!("/api/v1/namespaces/{}", self.name))? {
+            log::info!("Namespace {} does not exist", self.name);
+        }
+        Ok(())
+    }
+
+    pub fn get(client: &KubeClient, name: &str) -> RopsResult<Option<Self>> {
+        Ok(client
+            .get(&format!("/api/v1/namespaces/{name}"))?
+            .as_ref()
+            .and_then(Self::from_json))
+    }
+
+    /// List the preview namespaces of the cluster
+    pub fn list(client: &KubeClient) -> RopsResult<Vec<Self>> {
+        let selector = format!("{PREVIEW_LABEL}=true");
+        Ok(client
+            .list("/api/v1/namespaces", Some(&selector))?
+            .iter()
+            .filter_map(Self::from_json)
+            .collect())
+    }
+
+    fn from_json(namespace: &serde_json::Value) -> Option<Self> {
+        let metadata = &namespace["metadata"];
+        let annotations = &metadata["annotations"];
+        Some(Self {
+            name: metadata["name"].as_str()?.to_string(),
+            branch: annotations[BRANCH_ANNOTATION]
+                .as_str()
+                .unwrap_or_default()
+                .to_string(),
+            created: annotations[CREATED_ANNOTATION]
+                .as_str()
+                .and_then(|created| created.parse().ok())
+                .unwrap_or_default(),
+            blocks: annotations[BLOCKS_ANNOTATION]
+                .as_str()
+                .unwrap_or_default()
+                .split(',')
+                .filter(|block| !block.is_empty())
+                .map(str::to_string)
+                .collect(),
+        })
+    }
+
+    /// Check if the branch of the preview still exists in the origin remote
+    pub fn branch_exists(&self) -> bool {
+        match Command::new("git")
+            .arg("ls-remote")
+            .arg("--heads")
+            .arg("origin")
+            .arg(&self.branch)
+            .output()
+        {
+            Ok(output) if output.status.success() => !output.stdout.is_empty(),
+            // assume the branch exists when the remote cannot be queried
+            _ => true,
+        }
+    }
+}
+
+fn now_seconds() -> u64 {
+    std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .map(|duration| duration.as_secs())
+        .unwrap_or_default()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::utils::short_hash;
+
+    #[test]
+    fn namespace_names() {
+        assert_eq!(
+            PreviewNamespace::namespace_name("feature/login"),
+            format!("preview-feature-login-{}", short_hash("feature/login"))
+        );
+        // branches without valid characters
+        assert_eq!(
+            PreviewNamespace::namespace_name("ü/ö"),
+            format!("preview-{}", short_hash("ü/ö"))
+        );
+        let long = "feature/".to_string() + &"x".repeat(80);
+        let name = PreviewNamespace::namespace_name(&format!("{long}-a"));
+        assert_eq!(name.len(), 63);
+        assert_ne!(name, PreviewNamespace::namespace_name(&format!("{long}-b")));
+    }
+
+    #[test]
+    fn upstream_hosts() {
+        let preview = PreviewNamespace::new("feature/login");
+        let name = &preview.name;
+        assert_eq!(
+            preview.upstream(
+                "http://web-services.services.svc.cluster.local:80",
+                "services"
+            ),
+            format!("http://web-services.{name}.svc.cluster.local:80")
+        );
+        // only the namespace label of the host is replaced
+        assert_eq!(
+            preview.upstream("http://services.services.svc/services", "services"),
+            format!("http://services.{name}.svc/services")
+        );
+        assert_eq!(
+            preview.upstream("https://api.example.com", "services"),
+            "https://api.example.com"
+        );
+        assert_eq!(preview.upstream("not a url", "services"), "not a url");
+    }
+}
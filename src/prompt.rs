@@ -0,0 +1,53 @@
+use crate::error::{RopsError, RopsResult};
+use dialoguer::{Input, Select, theme::ColorfulTheme};
+use std::io::IsTerminal;
+
+/// Check if prompts can be shown - stdin and stderr are terminals
+pub fn is_interactive() -> bool {
+    std::io::stdin().is_terminal() && std::io::stderr().is_terminal()
+}
+
+/// Pick one of the named items, shown with their optional description
+pub fn select(
+    prompt: &str,
+    items: &[(String, Option<String>)],
+    default: Option<&str>,
+) -> RopsResult<String> {
+    if items.is_empty() {
+        return Err(RopsError::Error(format!("{prompt}: nothing to select")));
+    }
+    let width = items
+        .iter()
+        .map(|(name, _)| name.len())
+        .max()
+        .unwrap_or_default();
+    let labels: Vec<String> = items
+        .iter()
+        .map(|(name, description)| match description {
+            Some(description) => format!("{name:<width$}  {description}"),
+            None => name.clone(),
+        })
+        .collect();
+    let default = default
+        .and_then(|default| items.iter().position(|(name, _)| name == default))
+        .unwrap_or_default();
+    let index = Select::with_theme(&ColorfulTheme::default())
+        .with_prompt(prompt)
+        .items(&labels)
+        .default(default)
+        .max_length(20)
+        .interact_opt()
+        .map_err(|err| RopsError::Error(format!("Failed to read selection: {err}")))?
+        .ok_or_else(|| RopsError::Error("Selection cancelled".into()))?;
+    Ok(items[index].0.clone())
+}
+
+/// Ask the user to type the expected text to confirm an action
+pub fn confirm_typed(prompt: &str, expected: &str) -> RopsResult<bool> {
+    let typed: String = Input::with_theme(&ColorfulTheme::default())
+        .with_prompt(prompt)
+        .allow_empty(true)
+        .interact_text()
+        .map_err(|err| RopsError::Error(format!("Failed to read confirmation: {err}")))?;
+    Ok(typed.trim() == expected)
+}
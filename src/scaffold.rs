@@ -0,0 +1,422 @@
+use crate::{
+    error::{RopsError, RopsResult},
+    secrets::Sops,
+};
+use std::{
+    fs,
+    io::Write,
+    path::{Path, PathBuf},
+};
+
+/// Files of the builtin chart template, the Chart.yaml is generated
+const BUILTIN_TEMPLATE: &[(&str, &str)] = &[
+    (
+        ".helmignore",
+        r#".DS_Store
+.git/
+*.swp
+*.bak
+*.tmp
+"#,
+    ),
+    (
+        "values.yaml",
+        r#"replicaCount: 1
+
+image:
+  repository: ""
+  tag: ""
+  pullPolicy: IfNotPresent
+
+service:
+  type: ClusterIP
+  port: 80
+
+containerPort: 8080
+
+env: {}
+
+resources: {}
+"#,
+    ),
+    (
+        "templates/_helpers.tpl",
+        r#"{{- define "chart.labels" -}}
+app.kubernetes.io/name: {{ .Chart.Name }}
+app.kubernetes.io/instance: {{ .Release.Name }}
+app.kubernetes.io/version: {{ .Values.image.tag | default .Chart.AppVersion | quote }}
+app.kubernetes.io/managed-by: {{ .Release.Service }}
+{{- end }}
+
+{{- define "chart.selectorLabels" -}}
+app.kubernetes.io/name: {{ .Chart.Name }}
+app.kubernetes.io/instance: {{ .Release.Name }}
+{{- end }}
+"#,
+    ),
+    (
+        "templates/deployment.yaml",
+        r#"apiVersion: apps/v1
+kind: Deployment
+metadata:
+  name: {{ .Release.Name }}
+  labels:
+    {{- include "chart.labels" . | nindent 4 }}
+spec:
+  replicas: {{ .Values.replicaCount }}
+  selector:
+    matchLabels:
+      {{- include "chart.selectorLabels" . | nindent 6 }}
+  template:
+    metadata:
+      labels:
+        {{- include "chart.labels" . | nindent 8 }}
+    spec:
+      containers:
+        - name: {{ .Chart.Name }}
+          image: "{{ .Values.image.repository }}:{{ .Values.image.tag | default .Chart.AppVersion }}"
+          imagePullPolicy: {{ .Values.image.pullPolicy }}
+          ports:
+            - name: http
+              containerPort: {{ .Values.containerPort }}
+          {{- with .Values.env }}
+          env:
+            {{- range $name, $value := . }}
+            - name: {{ $name }}
+              value: {{ $value | quote }}
+            {{- end }}
+          {{- end }}
+          resources:
+            {{- toYaml .Values.resources | nindent 12 }}
+"#,
+    ),
+    (
+        "templates/service.yaml",
+        r#"apiVersion: v1
+kind: Service
+metadata:
+  name: {{ .Release.Name }}
+  labels:
+    {{- include "chart.labels" . | nindent 4 }}
+spec:
+  type: {{ .Values.service.type }}
+  ports:
+    - port: {{ .Values.service.port }}
+      targetPort: http
+      name: http
+  selector:
+    {{- include "chart.selectorLabels" . | nindent 4 }}
+"#,
+    ),
+];
+
+/// A new chart - its helm chart, charts config entry and vars
+pub struct ChartScaffold {
+    pub name: String,
+    /// directory the helm chart is created in
+    pub path: PathBuf,
+    pub namespace: Option<String>,
+    pub description: Option<String>,
+    /// add a block config routing `/{name}` to the chart service
+    pub block: bool,
+}
+
+impl ChartScaffold {
+    /// Check the chart name is a valid release name - a DNS-1123 label
+    pub fn validate_name(name: &str) -> RopsResult<()> {
+        let valid = !name.is_empty()
+            && name.len() <= 53
+            && name
+                .chars()
+                .all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || c == '-')
+            && !name.starts_with('-')
+            && !name.ends_with('-');
+        if valid {
+            Ok(())
+        } else {
+            Err(RopsError::Error(format!(
+                "Invalid chart name '{name}' - use at most 53 lowercase letters, digits and '-'"
+            )))
+        }
+    }
+
+    /// Check the template is `builtin` or a helm chart directory
+    pub fn validate_template(template: &str) -> RopsResult<()> {
+        if template == "builtin" || Path::new(template).join("Chart.yaml").is_file() {
+            Ok(())
+        } else {
+            Err(RopsError::Error(format!(
+                "Template '{template}' is not a helm chart - Chart.yaml not found"
+            )))
+        }
+    }
+
+    /// Create the helm chart from the builtin template or a template directory
+    pub fn create_chart(&self, template: &str) -> RopsResult<()> {
+        if self.path.exists() {
+            return Err(RopsError::Error(format!(
+                "Chart directory '{}' already exists",
+                self.path.display()
+            )));
+        }
+        let chart: serde_yaml::Value = if template == "builtin" {
+            for (file, content) in BUILTIN_TEMPLATE {
+                let path = self.path.join(file);
+                if let Some(parent) = path.parent() {
+                    fs::create_dir_all(parent)?;
+                }
+                fs::write(path, content)?;
+            }
+            serde_yaml::from_str(
+                "apiVersion: v2\ntype: application\nversion: 0.1.0\nappVersion: \"0.1.0\"",
+            )?
+        } else {
+            Self::validate_template(template)?;
+            let template = Path::new(template);
+            let chart_file = template.join("Chart.yaml");
+            copy_dir(template, &self.path)?;
+            serde_yaml::from_str(&fs::read_to_string(chart_file)?)?
+        };
+        // name and description follow the api version, as in charts created by helm
+        let mut metadata = serde_yaml::Mapping::new();
+        metadata.insert("apiVersion".into(), chart["apiVersion"].clone());
+        metadata.insert("name".into(), self.name.clone().into());
+        if let Some(description) = self.description.as_ref() {
+            metadata.insert("description".into(), description.clone().into());
+        }
+        if let serde_yaml::Value::Mapping(chart) = chart {
+            for (key, value) in chart {
+                if !metadata.contains_key(&key) {
+                    metadata.insert(key, value);
+                }
+            }
+        }
+        fs::write(
+            self.path.join("Chart.yaml"),
+            serde_yaml::to_string(&metadata)?,
+        )?;
+        log::info!("Created chart '{}' in {}", self.name, self.path.display());
+        Ok(())
+    }
+
+    /// The charts config entry of the chart
+    pub fn config_entry(&self) -> RopsResult<String> {
+        let mut entry = serde_yaml::Mapping::new();
+        // local charts are recognized by a leading dot
+        let chart = if self.path.is_absolute() {
+            self.path.display().to_string()
+        } else {
+            format!("./{}", self.path.display())
+        };
+        entry.insert("chart".into(), chart.into());
+        if let Some(namespace) = self.namespace.as_ref() {
+            entry.insert("namespace".into(), namespace.clone().into());
+        }
+        if let Some(description) = self.description.as_ref() {
+            entry.insert("description".into(), description.clone().into());
+        }
+        if self.block {
+            // the release name and namespace of each deploy are substituted in the upstream
+            let block: serde_yaml::Value = serde_yaml::from_str(&format!(
+                "name: {name}\nupstream: http://{{release}}.{{namespace}}.svc.cluster.local:80\n\
+                 routes:\n  - name: {name}\n    protocols: [http, https]\n    paths: [/{name}]",
+                name = self.name,
+            ))?;
+            entry.insert("block".into(), block);
+        }
+        let mut config = serde_yaml::Mapping::new();
+        config.insert(self.name.clone().into(), entry.into());
+        Ok(serde_yaml::to_string(&config)?)
+    }
+
+    /// Append the chart entry to the charts config, keeping its existing content
+    pub fn append_config(&self, config: &str) -> RopsResult<()> {
+        let path = Path::new(config);
+        let existing = fs::read_to_string(path).unwrap_or_default();
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        let mut file = fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)?;
+        if !existing.is_empty() && !existing.ends_with('\n') {
+            writeln!(file)?;
+        }
+        write!(file, "{}", self.config_entry()?)?;
+        log::info!("Added chart '{}' to {config}", self.name);
+        Ok(())
+    }
+
+    /// Create the values and an empty encrypted secrets file of each environment
+    pub fn create_vars(&self, vars: &str, envs: &[String]) -> RopsResult<()> {
+        let sops = Sops::default();
+        for env in envs {
+            let dir = Path::new(vars).join(env).join(&self.name);
+            fs::create_dir_all(&dir)?;
+            let values = dir.join("values.yaml");
+            if values.exists() {
+                log::info!("{} already exists", values.display());
+            } else {
+                // an empty document would reset the merged values
+                fs::write(&values, format!("# {} values of {env}\n{{}}\n", self.name))?;
+                log::info!("Created {}", values.display());
+            }
+            let secrets = dir.join("secrets.yaml");
+            if secrets.exists() {
+                log::info!("{} already exists", secrets.display());
+            } else {
+                sops.encrypt_new(&secrets, "{}\n")?;
+                log::info!("Created {}", secrets.display());
+            }
+        }
+        Ok(())
+    }
+}
+
+fn copy_dir(source: &Path, target: &Path) -> RopsResult<()> {
+    fs::create_dir_all(target)?;
+    for entry in fs::read_dir(source)? {
+        let entry = entry?;
+        let path = entry.path();
+        if entry.file_type()?.is_dir() {
+            copy_dir(&path, &target.join(entry.file_name()))?;
+        } else {
+            fs::copy(&path, target.join(entry.file_name()))?;
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::charts::Chart;
+    use std::collections::HashMap;
+
+    fn scaffold(path: PathBuf) -> ChartScaffold {
+        ChartScaffold {
+            name: "web".to_string(),
+            path,
+            namespace: Some("services".to_string()),
+            description: Some("Web frontend".to_string()),
+            block: true,
+        }
+    }
+
+    #[test]
+    fn chart_names() {
+        for name in ["web", "web-2", "a"] {
+            ChartScaffold::validate_name(name).unwrap();
+        }
+        for name in ["", "Web", "web_2", "-web", "web-", &"a".repeat(54)] {
+            assert!(ChartScaffold::validate_name(name).is_err(), "{name}");
+        }
+    }
+
+    #[test]
+    fn config_entry_block_follows_release() {
+        let entry = scaffold(PathBuf::from("charts/web"))
+            .config_entry()
+            .unwrap();
+        let charts: HashMap<String, Chart> = serde_yaml::from_str(&entry).unwrap();
+        let chart = &charts["web"];
+        assert_eq!(chart.chart, "./charts/web");
+        assert_eq!(chart.namespace.as_deref(), Some("services"));
+        assert_eq!(chart.description.as_deref(), Some("Web frontend"));
+        let block = chart.block.as_ref().unwrap();
+        assert!(block.validate("web.block").is_empty());
+        let upstream = |chart: &Chart, namespace: &str| {
+            block
+                .for_release(&chart.release_name("web", namespace), namespace)
+                .upstream
+        };
+        assert_eq!(
+            upstream(chart, "services"),
+            "http://web-services.services.svc.cluster.local:80"
+        );
+        // per environment namespaces and releases without the namespace
+        assert_eq!(
+            upstream(chart, "staging"),
+            "http://web-staging.staging.svc.cluster.local:80"
+        );
+        let chart = Chart {
+            append_namespace: false,
+            ..chart.clone()
+        };
+        assert_eq!(
+            upstream(&chart, "services"),
+            "http://web.services.svc.cluster.local:80"
+        );
+    }
+
+    #[test]
+    fn create_builtin_chart() {
+        let dir = tempfile::tempdir().unwrap();
+        let scaffold = scaffold(dir.path().join("web"));
+        scaffold.create_chart("builtin").unwrap();
+        for file in [
+            "values.yaml",
+            "templates/deployment.yaml",
+            "templates/service.yaml",
+        ] {
+            assert!(scaffold.path.join(file).is_file(), "{file}");
+        }
+        let chart = fs::read_to_string(scaffold.path.join("Chart.yaml")).unwrap();
+        assert!(
+            chart.starts_with("apiVersion: v2\nname: web\ndescription: Web frontend\n"),
+            "{chart}"
+        );
+        // existing charts are never overwritten
+        assert!(scaffold.create_chart("builtin").is_err());
+    }
+
+    #[test]
+    fn create_chart_from_template() {
+        let dir = tempfile::tempdir().unwrap();
+        let template = dir.path().join("template");
+        fs::create_dir_all(template.join("templates")).unwrap();
+        fs::write(
+            template.join("Chart.yaml"),
+            "apiVersion: v2\nname: template\nversion: 1.2.0\n",
+        )
+        .unwrap();
+        fs::write(template.join("templates/job.yaml"), "kind: Job\n").unwrap();
+        let template = template.display().to_string();
+        ChartScaffold::validate_template(&template).unwrap();
+        assert!(ChartScaffold::validate_template(&dir.path().display().to_string()).is_err());
+
+        let scaffold = ChartScaffold {
+            description: None,
+            ..scaffold(dir.path().join("web"))
+        };
+        scaffold.create_chart(&template).unwrap();
+        assert_eq!(
+            fs::read_to_string(scaffold.path.join("templates/job.yaml")).unwrap(),
+            "kind: Job\n"
+        );
+        let chart: serde_yaml::Value =
+            serde_yaml::from_str(&fs::read_to_string(scaffold.path.join("Chart.yaml")).unwrap())
+                .unwrap();
+        assert_eq!(chart["name"], serde_yaml::Value::from("web"));
+        assert_eq!(chart["version"], serde_yaml::Value::from("1.2.0"));
+    }
+
+    #[test]
+    fn append_config_keeps_content() {
+        let dir = tempfile::tempdir().unwrap();
+        let config = dir.path().join("charts.yaml");
+        fs::write(&config, "api:\n  chart: ./api").unwrap();
+        let scaffold = ChartScaffold {
+            block: false,
+            ..scaffold(PathBuf::from("web"))
+        };
+        scaffold
+            .append_config(&config.display().to_string())
+            .unwrap();
+        let charts: HashMap<String, Chart> =
+            serde_yaml::from_str(&fs::read_to_string(&config).unwrap()).unwrap();
+        assert_eq!(charts["api"].chart, "./api");
+        assert_eq!(charts["web"].chart, "./web");
+    }
+}
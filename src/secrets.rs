@@ -0,0 +1,394 @@
+use crate::{
+    error::{RopsError, RopsResult},
+    settings::Settings,
+    utils,
+};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+use tempfile::NamedTempFile;
+
+#[derive(clap::Subcommand, Debug, Clone)]
+pub enum SecretsCommand {
+    /// Edit secrets with sops, creating the file if it does not exist
+    Edit {
+        /// K8s environment of the secrets
+        env: String,
+        /// Chart of the secrets - environment secrets if not given
+        chart: Option<String>,
+        /// override additional variables path
+        #[arg(short, long)]
+        vars: Option<String>,
+    },
+    /// Print decrypted secrets
+    View {
+        /// K8s environment of the secrets
+        env: String,
+        /// Chart of the secrets - environment secrets if not given
+        chart: Option<String>,
+        /// override additional variables path
+        #[arg(short, long)]
+        vars: Option<String>,
+    },
+    /// Set secret values as key.path=value
+    Set {
+        /// K8s environment of the secrets
+        env: String,
+        /// Values to set as key.path=value, list items are addressed as key.list[0]
+        #[arg(required = true)]
+        values: Vec<String>,
+        /// Chart of the secrets - environment secrets if not given
+        #[arg(short, long)]
+        chart: Option<String>,
+        /// override additional variables path
+        #[arg(short, long)]
+        vars: Option<String>,
+    },
+    /// Rotate the data key of secrets - all secrets of the environment if no chart is given
+    Rotate {
+        /// K8s environment of the secrets
+        env: String,
+        /// Chart of the secrets
+        chart: Option<String>,
+        /// override additional variables path
+        #[arg(short, long)]
+        vars: Option<String>,
+    },
+}
+
+/// Wrapper of the sops binary used to decrypt and manage secrets files
+pub struct Sops {
+    pub binary: PathBuf,
+}
+
+impl Default for Sops {
+    fn default() -> Self {
+        // prefer the sops binary managed by `rops tools update sops`
+        let binary = utils::home_bin("sops")
+            .ok()
+            .filter(|path| path.exists())
+            .unwrap_or_else(|| "sops".into());
+        Self { binary }
+    }
+}
+
+impl SecretsCommand {
+    /// Run the secrets command
+    pub fn run(&self, settings: &Settings) -> RopsResult<()> {
+        let sops = Sops::default();
+        match self {
+            Self::Edit { env, chart, vars } => {
+                let path = Self::secrets_path(settings, env, chart.as_deref(), vars.as_deref())?;
+                if path.exists() {
+                    sops.ensure_encrypted(&path)?;
+                } else if let Some(parent) = path.parent() {
+                    std::fs::create_dir_all(parent)?;
+                }
+                log::info!("{} edit {}", sops.binary.display(), path.display());
+                let status = sops.command().arg("edit").arg(&path).status()?;
+                if status.success() {
+                    Ok(())
+                } else {
+                    Err(RopsError::Error(format!(
+                        "Failed to edit '{}'",
+                        path.display()
+                    )))
+                }
+            }
+            Self::View { env, chart, vars } => {
+                let path = Self::secrets_path(settings, env, chart.as_deref(), vars.as_deref())?;
+                let content = sops.decrypt(&path)?;
+                print!("{}", String::from_utf8_lossy(&content));
+                Ok(())
+            }
+            Self::Set {
+                env,
+                values,
+                chart,
+                vars,
+            } => {
+                let path = Self::secrets_path(settings, env, chart.as_deref(), vars.as_deref())?;
+                if !path.exists() {
+                    return Err(RopsError::Error(format!(
+                        "Secrets file '{}' not found - create it with `rops secrets edit`",
+                        path.display()
+                    )));
+                }
+                sops.ensure_encrypted(&path)?;
+                for value in values {
+                    let (key, value) = value.split_once('=').ok_or_else(|| {
+                        RopsError::Error(format!(
+                            "Invalid value '{value}' - expected key.path=value"
+                        ))
+                    })?;
+                    sops.set(&path, key, value)?;
+                }
+                Ok(())
+            }
+            Self::Rotate { env, chart, vars } => {
+                let path = Self::secrets_path(settings, env, chart.as_deref(), vars.as_deref())?;
+                let mut paths = vec![path.clone()];
+                if chart.is_none()
+                    && let Some(env_dir) = path.parent()
+                    && env_dir.is_dir()
+                {
+                    for entry in std::fs::read_dir(env_dir)? {
+                        let chart_secrets = entry?.path().join("secrets.yaml");
+                        if chart_secrets.is_file() {
+                            paths.push(chart_secrets);
+                        }
+                    }
+                }
+                for path in paths.iter().filter(|path| path.exists()) {
+                    sops.ensure_encrypted(path)?;
+                    sops.rotate(path)?;
+                }
+                Ok(())
+            }
+        }
+    }
+
+    /// The secrets file of an environment or of a chart within an environment
+    ///
+    /// Located the same way deploys do - `{vars}/{env}/secrets.yaml` or
+    /// `{vars}/{env}/{chart}/secrets.yaml`
+    fn secrets_path(
+        settings: &Settings,
+        env: &str,
+        chart: Option<&str>,
+        vars: Option<&str>,
+    ) -> RopsResult<PathBuf> {
+        if !settings.charts.envs.contains_key(env) {
+            log::warn!("Environment '{env}' not found in charts settings");
+        }
+        let vars_path = settings
+            .charts
+            .get_vars_path(env.to_string(), vars)
+            .ok_or_else(|| {
+                RopsError::Error(
+                    "Variables path not configured - set charts.vars in rops.toml or pass --vars"
+                        .into(),
+                )
+            })?;
+        let mut path = PathBuf::from(vars_path);
+        if let Some(chart) = chart {
+            path.push(chart);
+        }
+        path.push("secrets.yaml");
+        Ok(path)
+    }
+}
+
+impl Sops {
+    pub fn command(&self) -> Command {
+        Command::new(&self.binary)
+    }
+
+    /// Decrypt a secrets file and return the plain text content
+    pub fn decrypt(&self, path: &Path) -> RopsResult<Vec<u8>> {
+        if !path.exists() {
+            return Err(RopsError::Error(format!(
+                "Secrets file '{}' not found",
+                path.display()
+            )));
+        }
+        let output = self
+            .command()
+            .arg("--decrypt")
+            .arg(path)
+            .output()
+            .map_err(|err| {
+                RopsError::Error(format!(
+                    "Failed to run '{}' - install it with `rops tools update sops`: {err}",
+                    self.binary.display()
+                ))
+            })?;
+        if output.status.success() {
+            Ok(output.stdout)
+        } else {
+            Err(self.decrypt_error(path, &String::from_utf8_lossy(&output.stderr)))
+        }
+    }
+
+    /// Decrypt a secrets file into a temporary file which is removed when dropped
+    pub fn decrypt_to_temp(&self, path: &Path) -> RopsResult<NamedTempFile> {
+        let content = self.decrypt(path)?;
+        let mut file = tempfile::Builder::new()
+            .prefix("rops-secrets-")
+            .suffix(".yaml")
+            .tempfile()?;
+        std::io::Write::write_all(&mut file, &content)?;
+        Ok(file)
+    }
+
+    fn decrypt_error(&self, path: &Path, stderr: &str) -> RopsError {
+        let stderr = stderr.trim();
+        if stderr.contains("Failed to get the data key") {
+            RopsError::Error(format!(
+                "Failed to decrypt '{}' - none of the keys it is encrypted with are available. \
+                 Check SOPS_AGE_KEY_FILE, your cloud credentials or .sops.yaml: {stderr}",
+                path.display()
+            ))
+        } else if stderr.contains("sops metadata not found") {
+            RopsError::Error(format!(
+                "Failed to decrypt '{}' - the file is not encrypted with sops",
+                path.display()
+            ))
+        } else {
+            RopsError::Error(format!("Failed to decrypt '{}': {stderr}", path.display()))
+        }
+    }
+
+    /// Check if a file is encrypted with sops - it has the sops metadata key
+    pub fn is_encrypted(path: &Path) -> RopsResult<bool> {
+        let content: serde_yaml::Value = serde_yaml::from_str(&std::fs::read_to_string(path)?)?;
+        Ok(content.get("sops").is_some())
+    }
+
+    /// Refuse to operate on plain text secrets files
+    pub fn ensure_encrypted(&self, path: &Path) -> RopsResult<()> {
+        if Self::is_encrypted(path)? {
+            Ok(())
+        } else {
+            Err(RopsError::Error(format!(
+                "Secrets file '{}' is not encrypted with sops - encrypt it with `sops encrypt --in-place` first",
+                path.display()
+            )))
+        }
+    }
+
+    /// Set a value in an encrypted file - the key is a dot separated path
+    pub fn set(&self, path: &Path, key: &str, value: &str) -> RopsResult<()> {
+        let index = sops_index(key)?;
+        // numbers and booleans are set as such, anything else as a string
+        let value = match serde_json::from_str::<serde_json::Value>(value) {
+            Ok(value @ (serde_json::Value::Number(_) | serde_json::Value::Bool(_))) => value,
+            _ => serde_json::Value::from(value),
+        };
+        log::info!(
+            "{} set {} '{index}' ***",
+            self.binary.display(),
+            path.display()
+        );
+        let output = self
+            .command()
+            .arg("set")
+            .arg(path)
+            .arg(&index)
+            .arg(value.to_string())
+            .output()?;
+        if output.status.success() {
+            Ok(())
+        } else {
+            Err(self.decrypt_error(path, &String::from_utf8_lossy(&output.stderr)))
+        }
+    }
+
+    /// Create an encrypted file from plain yaml content
+    ///
+    /// The content is encrypted as if it was read from the path, so the creation
+    /// rules of `.sops.yaml` matching the path apply.
+    pub fn encrypt_new(&self, path: &Path, content: &str) -> RopsResult<()> {
+        log::info!(
+            "{} encrypt --filename-override {}",
+            self.binary.display(),
+            path.display()
+        );
+        let mut child = self
+            .command()
+            .arg("encrypt")
+            .arg("--filename-override")
+            .arg(path)
+            .arg("--input-type")
+            .arg("yaml")
+            .arg("--output-type")
+            .arg("yaml")
+            .arg("/dev/stdin")
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .spawn()
+            .map_err(|err| {
+                RopsError::Error(format!(
+                    "Failed to run '{}' - install it with `rops tools update sops`: {err}",
+                    self.binary.display()
+                ))
+            })?;
+        if let Some(mut stdin) = child.stdin.take() {
+            stdin.write_all(content.as_bytes())?;
+        }
+        let output = child.wait_with_output()?;
+        if !output.status.success() {
+            return Err(RopsError::Error(format!(
+                "Failed to encrypt '{}' - check the creation rules of .sops.yaml: {}",
+                path.display(),
+                String::from_utf8_lossy(&output.stderr).trim()
+            )));
+        }
+        if let Some(parent) = path.parent() {
+            std::fs::create_dir_all(parent)?;
+        }
+        std::fs::write(path, output.stdout)?;
+        Ok(())
+    }
+
+    /// Rotate the data key of an encrypted file in place
+    pub fn rotate(&self, path: &Path) -> RopsResult<()> {
+        let mut command = self.command();
+        command.arg("--rotate").arg("--in-place").arg(path);
+        if utils::StreamCommand::new(command).run()? {
+            Ok(())
+        } else {
+            Err(RopsError::Error(format!(
+                "Failed to rotate the data key of '{}'",
+                path.display()
+            )))
+        }
+    }
+}
+
+/// Convert a dot separated key path to a sops index, e.g. `a.b[0]` to `["a"]["b"][0]`
+///
+/// Only `[n]` suffixes index lists, numeric parts like `404` are map keys.
+fn sops_index(key: &str) -> RopsResult<String> {
+    let invalid = || RopsError::Error(format!("Invalid secret key '{key}'"));
+    let mut index = String::new();
+    for part in key.split('.') {
+        let (name, mut items) = part.split_at(part.find('[').unwrap_or(part.len()));
+        if name.is_empty() && items.is_empty() {
+            return Err(invalid());
+        }
+        if !name.is_empty() {
+            index.push_str(&format!("[{}]", serde_json::Value::from(name)));
+        }
+        while !items.is_empty() {
+            let (item, rest) = items
+                .strip_prefix('[')
+                .and_then(|items| items.split_once(']'))
+                .ok_or_else(invalid)?;
+            let item = item.parse::<usize>().map_err(|_| invalid())?;
+            index.push_str(&format!("[{item}]"));
+            items = rest;
+        }
+    }
+    Ok(index)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sops_indexes() {
+        assert_eq!(sops_index("db.password").unwrap(), r#"["db"]["password"]"#);
+        assert_eq!(sops_index("pages.404").unwrap(), r#"["pages"]["404"]"#);
+        assert_eq!(
+            sops_index("users[0].tokens[1][2]").unwrap(),
+            r#"["users"][0]["tokens"][1][2]"#
+        );
+        assert_eq!(sops_index(r#"a"b"#).unwrap(), r#"["a\"b"]"#);
+        for key in ["", "a..b", "a[", "a[x]", "a[0]b", "a[-1]"] {
+            assert!(sops_index(key).is_err(), "{key}");
+        }
+    }
+}
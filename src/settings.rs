@@ -1,4 +1,4 @@
-use super::{blocks, charts, docker, git, system};
+use super::{blocks, charts, docker, git, notifications, system};
 use serde::{Deserialize, Serialize};
 use std::fs;
 use std::path::Path;
@@ -21,6 +21,8 @@ pub struct Settings {
     pub charts: charts::ChartsSettings,
     #[serde(default)]
     pub blocks: blocks::BlockSettings,
+    #[serde(default)]
+    pub notifications: notifications::NotificationSettings,
 }
 
 impl Settings {
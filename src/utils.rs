@@ -98,6 +98,9 @@ pub struct StreamCommand {
     pub command: Command,
     pub dry_run: bool,
     pub skip_error: Option<String>,
+    pub inherit_stdout: bool,
+    /// only the exit status decides success, not the absence of stderr output
+    pub strict: bool,
 }
 
 impl StreamCommand {
@@ -106,6 +109,8 @@ impl StreamCommand {
             command,
             dry_run: false,
             skip_error: None,
+            inherit_stdout: false,
+            strict: false,
         }
     }
 
@@ -120,24 +125,32 @@ impl StreamCommand {
         self
     }
 
+    /// Write the command stdout to the process stdout rather than the logger
+    pub fn with_inherit_stdout(mut self, inherit_stdout: bool) -> Self {
+        self.inherit_stdout = inherit_stdout;
+        self
+    }
+
+    /// Fail on a non-zero exit status even if nothing was written to stderr
+    pub fn with_strict(mut self, strict: bool) -> Self {
+        self.strict = strict;
+        self
+    }
+
     pub fn run(&mut self) -> RopsResult<bool> {
         log::info!("{}", self.format_command());
         if self.dry_run {
             log::info!("Dry run mode enabled, skipping actual command execution.");
             return Ok(true);
         }
-        let mut child = self
-            .command
-            .stdout(Stdio::piped())
-            .stderr(Stdio::piped())
-            .spawn()?;
+        let stdout = if self.inherit_stdout {
+            Stdio::inherit()
+        } else {
+            Stdio::piped()
+        };
+        let mut child = self.command.stdout(stdout).stderr(Stdio::piped()).spawn()?;
         //
-        let stdout = BufReader::new(
-            child
-                .stdout
-                .take()
-                .ok_or_else(|| RopsError::Error("Failed to capture stdout".into()))?,
-        );
+        let stdout = child.stdout.take().map(BufReader::new);
         let stderr = BufReader::new(
             child
                 .stderr
@@ -145,8 +158,10 @@ impl StreamCommand {
                 .ok_or_else(|| RopsError::Error("Failed to capture stderr".into()))?,
         );
         let stdout_thread = std::thread::spawn(move || {
-            for line in stdout.lines().map_while(Result::ok) {
-                log::info!("{}", line);
+            if let Some(stdout) = stdout {
+                for line in stdout.lines().map_while(Result::ok) {
+                    log::info!("{}", line);
+                }
             }
         });
 
@@ -173,7 +188,7 @@ impl StreamCommand {
         let error_lines = stderr_thread
             .join()
             .map_err(|e| RopsError::Error(format!("Failed to join stderr thread: {:?}", e)))?;
-        if status.success() || error_lines == 0 {
+        if status.success() || (!self.strict && error_lines == 0) {
             Ok(true)
         } else {
             Ok(false)
@@ -206,6 +221,230 @@ impl StreamCommand {
     }
 }
 
+/// Deep merge a YAML value into another the way helm merges values files
+///
+/// Mappings are merged recursively, null values remove keys and any other
+/// value replaces the existing one
+pub fn merge_yaml(base: &mut serde_yaml::Value, overlay: serde_yaml::Value) {
+    match (base, overlay) {
+        (serde_yaml::Value::Mapping(base), serde_yaml::Value::Mapping(overlay)) => {
+            for (key, value) in overlay {
+                if value.is_null() {
+                    base.remove(&key);
+                } else if let Some(existing) = base.get_mut(&key) {
+                    merge_yaml(existing, value);
+                } else {
+                    base.insert(key, value);
+                }
+            }
+        }
+        (base, overlay) => *base = overlay,
+    }
+}
+
+/// Split helm `--set` values into `path=value` pairs
+///
+/// Commas separate pairs unless they are escaped with a backslash or inside a
+/// `{a,b}` list, escapes are kept for `set_yaml_path`.
+pub fn split_set_values(set: &str) -> Vec<String> {
+    let mut pairs = vec![];
+    let mut current = String::new();
+    let mut depth = 0usize;
+    let mut chars = set.chars();
+    while let Some(c) = chars.next() {
+        match c {
+            '\\' => {
+                current.push(c);
+                if let Some(escaped) = chars.next() {
+                    current.push(escaped);
+                }
+                continue;
+            }
+            '{' => depth += 1,
+            '}' => depth = depth.saturating_sub(1),
+            ',' if depth == 0 => {
+                pairs.push(std::mem::take(&mut current));
+                continue;
+            }
+            _ => {}
+        }
+        current.push(c);
+    }
+    pairs.push(current);
+    pairs.into_iter().filter(|pair| !pair.is_empty()).collect()
+}
+
+/// Parse a `--set` value - a `{a,b}` list or a scalar with backslash escapes
+fn parse_set_value(value: &str) -> serde_yaml::Value {
+    if let Some(items) = value
+        .strip_prefix('{')
+        .and_then(|value| value.strip_suffix('}'))
+    {
+        if items.is_empty() {
+            return serde_yaml::Value::Sequence(vec![]);
+        }
+        return serde_yaml::Value::Sequence(
+            split_set_values(items)
+                .iter()
+                .map(|item| parse_set_value(item))
+                .collect(),
+        );
+    }
+    let mut unescaped = String::new();
+    let mut chars = value.chars();
+    while let Some(c) = chars.next() {
+        match c {
+            '\\' => unescaped.extend(chars.next()),
+            c => unescaped.push(c),
+        }
+    }
+    typed_set_value(unescaped)
+}
+
+/// Type a `--set` scalar like helm - booleans, null and base-10 integers without a
+/// leading zero are typed, anything else, e.g. `1.10`, stays a string
+fn typed_set_value(value: String) -> serde_yaml::Value {
+    if value.eq_ignore_ascii_case("true") {
+        serde_yaml::Value::Bool(true)
+    } else if value.eq_ignore_ascii_case("false") {
+        serde_yaml::Value::Bool(false)
+    } else if value.eq_ignore_ascii_case("null") {
+        serde_yaml::Value::Null
+    } else if value == "0" {
+        serde_yaml::Value::from(0)
+    } else if !value.starts_with('0')
+        && let Ok(integer) = value.parse::<i64>()
+    {
+        serde_yaml::Value::from(integer)
+    } else {
+        serde_yaml::Value::from(value)
+    }
+}
+
+/// Set a value at a helm `--set` style path, e.g. `image.tag` or `hosts[0].name`
+///
+/// Values are typed like helm does - only booleans, null and integers keep their type,
+/// other values such as `1.10` are strings - and `{a,b}` sets a list
+pub fn set_yaml_path(root: &mut serde_yaml::Value, path: &str, value: &str) {
+    let value = parse_set_value(value);
+    let mut current = root;
+    for part in path.split('.') {
+        let (key, indices) = match part.split_once('[') {
+            Some((key, rest)) => (
+                key,
+                rest.split('[')
+                    .filter_map(|index| index.trim_end_matches(']').parse::<usize>().ok())
+                    .collect(),
+            ),
+            None => (part, vec![]),
+        };
+        if !current.is_mapping() {
+            *current = serde_yaml::Value::Mapping(Default::default());
+        }
+        current = current
+            .as_mapping_mut()
+            .expect("value is a mapping")
+            .entry(serde_yaml::Value::from(key))
+            .or_insert(serde_yaml::Value::Null);
+        for index in indices {
+            if !current.is_sequence() {
+                *current = serde_yaml::Value::Sequence(vec![]);
+            }
+            let sequence = current.as_sequence_mut().expect("value is a sequence");
+            if sequence.len() <= index {
+                sequence.resize(index + 1, serde_yaml::Value::Null);
+            }
+            current = &mut sequence[index];
+        }
+    }
+    *current = value;
+}
+
+/// Current UTC time formatted as RFC 3339, e.g. `2025-01-31T12:00:00Z`
+pub fn now_rfc3339() -> String {
+    let seconds = std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .map(|duration| duration.as_secs())
+        .unwrap_or_default();
+    format_rfc3339(seconds)
+}
+
+/// Format seconds since the unix epoch as RFC 3339 in UTC
+pub fn format_rfc3339(seconds: u64) -> String {
+    let (days, time) = (seconds / 86400, seconds % 86400);
+    // civil from days algorithm - http://howardhinnant.github.io/date_algorithms.html
+    let z = days as i64 + 719468;
+    let era = z.div_euclid(146097);
+    let doe = z - era * 146097;
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + i64::from(month <= 2);
+    format!(
+        "{year:04}-{month:02}-{day:02}T{:02}:{:02}:{:02}Z",
+        time / 3600,
+        time % 3600 / 60,
+        time % 60
+    )
+}
+
+/// Convert a string into a valid DNS-1123 label of at most `max_length` characters
+pub fn dns_label(value: &str, max_length: usize) -> String {
+    let mut label = String::new();
+    for c in value.to_lowercase().chars() {
+        if c.is_ascii_alphanumeric() {
+            label.push(c);
+        } else if !label.is_empty() && !label.ends_with('-') {
+            label.push('-');
+        }
+    }
+    label.truncate(max_length);
+    label.trim_end_matches('-').to_string()
+}
+
+/// Convert a string into a valid DNS-1123 label of at most `max_length` characters
+/// suffixed with a short hash of the string, so that strings with the same label
+/// prefix get distinct labels
+pub fn hashed_dns_label(value: &str, max_length: usize) -> String {
+    let hash = short_hash(value);
+    let label = dns_label(value, max_length.saturating_sub(hash.len() + 1));
+    if label.is_empty() {
+        hash
+    } else {
+        format!("{label}-{hash}")
+    }
+}
+
+/// A short stable hash of a string - 8 hex digits of its 32-bit FNV-1a hash
+pub fn short_hash(value: &str) -> String {
+    let hash = value.bytes().fold(0x811c9dc5u32, |hash, byte| {
+        (hash ^ u32::from(byte)).wrapping_mul(0x01000193)
+    });
+    format!("{hash:08x}")
+}
+
+/// Match a value against a pattern where `*` matches any characters
+pub fn glob_match(pattern: &str, value: &str) -> bool {
+    let mut parts = pattern.split('*');
+    let first = parts.next().unwrap_or_default();
+    let Some(mut rest) = value.strip_prefix(first) else {
+        return false;
+    };
+    let parts: Vec<&str> = parts.collect();
+    let Some((last, middle)) = parts.split_last() else {
+        return rest.is_empty();
+    };
+    for part in middle {
+        match rest.find(part) {
+            Some(index) => rest = &rest[index + part.len()..],
+            None => return false,
+        }
+    }
+    rest.len() >= last.len() && rest.ends_with(last)
+}
+
 pub fn rimraf(path: &str) -> RopsResult<()> {
     if std::path::Path::new(path).exists() {
         std::fs::remove_dir_all(path).map_err(|err| {
@@ -224,3 +463,129 @@ pub fn random_base_64(length: usize) -> RopsResult<String> {
     let encoded_string = general_purpose::URL_SAFE_NO_PAD.encode(&random_bytes);
     Ok(encoded_string)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn yaml(value: &str) -> serde_yaml::Value {
+        serde_yaml::from_str(value).unwrap()
+    }
+
+    #[test]
+    fn merge_yaml_like_helm() {
+        let mut base = yaml("image: {repository: web, tag: '1'}\nports: [80, 443]\nremoved: x");
+        merge_yaml(
+            &mut base,
+            yaml("image: {tag: '2'}\nports: [8080]\nremoved: null\nadded: true"),
+        );
+        assert_eq!(
+            base,
+            yaml("image: {repository: web, tag: '2'}\nports: [8080]\nadded: true")
+        );
+    }
+
+    #[test]
+    fn set_yaml_paths() {
+        let mut root = yaml("image: {repository: web}");
+        set_yaml_path(&mut root, "image.tag", "1.0");
+        set_yaml_path(&mut root, "replicas", "3");
+        set_yaml_path(&mut root, "debug", "true");
+        set_yaml_path(&mut root, "hosts[1].name", "example.com");
+        assert_eq!(
+            root,
+            yaml(
+                "image: {repository: web, tag: '1.0'}\nreplicas: 3\ndebug: true\n\
+                 hosts: [null, {name: example.com}]"
+            )
+        );
+        assert_eq!(root["image"]["tag"], serde_yaml::Value::from("1.0"));
+    }
+
+    #[test]
+    fn set_values_typed_like_helm() {
+        let typed = |value: &str| typed_set_value(value.to_string());
+        assert_eq!(typed("1.10"), serde_yaml::Value::from("1.10"));
+        assert_eq!(typed("1e3"), serde_yaml::Value::from("1e3"));
+        assert_eq!(typed("0123"), serde_yaml::Value::from("0123"));
+        assert_eq!(typed("yes"), serde_yaml::Value::from("yes"));
+        assert_eq!(typed(""), serde_yaml::Value::from(""));
+        assert_eq!(typed("0"), serde_yaml::Value::from(0));
+        assert_eq!(typed("-42"), serde_yaml::Value::from(-42));
+        assert_eq!(typed("TRUE"), serde_yaml::Value::Bool(true));
+        assert_eq!(typed("False"), serde_yaml::Value::Bool(false));
+        assert_eq!(typed("null"), serde_yaml::Value::Null);
+    }
+
+    #[test]
+    fn set_yaml_lists_and_escapes() {
+        let mut root = serde_yaml::Value::Null;
+        for pair in split_set_values(r"a=1,list={x,y\,z},escaped=b\,c,empty={}") {
+            let (path, value) = pair.split_once('=').unwrap();
+            set_yaml_path(&mut root, path, value);
+        }
+        assert_eq!(
+            root,
+            yaml("a: 1\nlist: [x, 'y,z']\nescaped: 'b,c'\nempty: []")
+        );
+    }
+
+    #[test]
+    fn split_set_pairs() {
+        assert_eq!(
+            split_set_values(r"a=1,b={x,y},c=d\,e"),
+            vec!["a=1", "b={x,y}", r"c=d\,e"]
+        );
+        assert_eq!(split_set_values(""), Vec::<String>::new());
+    }
+
+    #[test]
+    fn format_rfc3339_epochs() {
+        assert_eq!(format_rfc3339(0), "1970-01-01T00:00:00Z");
+        assert_eq!(format_rfc3339(951782400), "2000-02-29T00:00:00Z");
+        assert_eq!(format_rfc3339(1709210096), "2024-02-29T12:34:56Z");
+        assert_eq!(format_rfc3339(4102444799), "2099-12-31T23:59:59Z");
+        // 2100 is not a leap year
+        assert_eq!(format_rfc3339(4107542400), "2100-03-01T00:00:00Z");
+    }
+
+    #[test]
+    fn stream_command_silent_failure() {
+        let mut command = Command::new("sh");
+        command.arg("-c").arg("exit 1");
+        assert!(StreamCommand::new(command).run().unwrap());
+        let mut command = Command::new("sh");
+        command.arg("-c").arg("exit 1");
+        assert!(!StreamCommand::new(command).with_strict(true).run().unwrap());
+        let mut command = Command::new("sh");
+        command.arg("-c").arg("echo warning >&2");
+        assert!(StreamCommand::new(command).with_strict(true).run().unwrap());
+    }
+
+    #[test]
+    fn dns_labels() {
+        assert_eq!(dns_label("feature/JIRA-12_fix", 63), "feature-jira-12-fix");
+        assert_eq!(dns_label("--a..b--", 63), "a-b");
+        assert_eq!(dns_label("abcdef-ghi", 7), "abcdef");
+        assert_eq!(dns_label("ÄÖÜ/_", 63), "");
+    }
+
+    #[test]
+    fn hashed_dns_labels() {
+        assert_eq!(short_hash(""), "811c9dc5");
+        assert_eq!(short_hash("a"), "e40c292c");
+        let label = hashed_dns_label("feature/JIRA-12", 63);
+        assert_eq!(
+            label,
+            format!("feature-jira-12-{}", short_hash("feature/JIRA-12"))
+        );
+        // only the hash when nothing of the value is valid
+        assert_eq!(hashed_dns_label("ÄÖÜ", 63), short_hash("ÄÖÜ"));
+        // truncated values stay distinct
+        let long = "a".repeat(80);
+        let first = hashed_dns_label(&format!("{long}-one"), 40);
+        let second = hashed_dns_label(&format!("{long}-two"), 40);
+        assert_eq!(first.len(), 40);
+        assert_ne!(first, second);
+    }
+}
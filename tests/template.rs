@@ -0,0 +1,41 @@
+use std::{fs, process::Command};
+
+/// Rendered manifests are the only output on stdout, logs go to stderr
+#[test]
+fn template_stdout_is_yaml() {
+    let dir = tempfile::tempdir().unwrap();
+    fs::write(
+        dir.path().join("rops.toml"),
+        "[git]\n[docker]\n[project]\n[charts]\nconfig = \"charts.yaml\"\nvars = \"vars\"\n[charts.envs]\nprod = \"prod-cluster\"\n",
+    )
+    .unwrap();
+    fs::write(
+        dir.path().join("charts.yaml"),
+        "web:\n  chart: ./k8s\n  type: manifests\n  namespace: services\n",
+    )
+    .unwrap();
+    fs::create_dir_all(dir.path().join("k8s")).unwrap();
+    fs::create_dir_all(dir.path().join("vars/prod")).unwrap();
+    fs::write(
+        dir.path().join("k8s/config.yaml"),
+        "apiVersion: v1\nkind: ConfigMap\nmetadata:\n  name: web\ndata:\n  tag: \"1.10\"\n",
+    )
+    .unwrap();
+    let output = Command::new(env!("CARGO_BIN_EXE_rops"))
+        .args(["charts", "template", "web", "--env", "prod"])
+        .current_dir(dir.path())
+        .env("RUST_LOG", "info")
+        .output()
+        .unwrap();
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(output.status.success(), "{stderr}");
+    assert!(stderr.contains("Manifests:"), "{stderr}");
+
+    let stdout = String::from_utf8(output.stdout).unwrap();
+    let documents: Vec<serde_yaml::Value> = serde_yaml::Deserializer::from_str(&stdout)
+        .map(|document| serde::Deserialize::deserialize(document).unwrap())
+        .collect();
+    assert_eq!(documents.len(), 1, "{stdout}");
+    assert_eq!(documents[0]["kind"], serde_yaml::Value::from("ConfigMap"));
+    assert_eq!(documents[0]["data"]["tag"], serde_yaml::Value::from("1.10"));
+}